@@ -0,0 +1,190 @@
+use std::{
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::Error;
+use http::{Request, Response, header};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+
+use crate::{Outgoing, Service, http_util::pool::Pool, shutdown::Shutdown};
+
+fn text_response(
+    status: u16,
+    content_type: &str,
+    text: String,
+) -> Result<Response<Outgoing>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Full::new(Bytes::from(text)).map_err(Error::new).boxed())?)
+}
+
+/// Health check endpoint.
+///
+/// Responds with `503 Service Unavailable` once shutdown is started,
+/// so that load balancers stop sending new requests.
+#[derive(Clone, Default, Debug)]
+pub struct Health;
+
+impl Service for Health {
+    async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+        let stopped = req
+            .extensions()
+            .get::<Shutdown>()
+            .is_some_and(|shutdown| shutdown.is_stopped());
+        if stopped {
+            text_response(503, "text/plain", "shutting down\n".into())
+        } else {
+            text_response(200, "text/plain", "ok\n".into())
+        }
+    }
+}
+
+/// Counters of a single listener.
+#[derive(Default, Debug)]
+pub struct ListenerStats {
+    pub connections_total: AtomicU64,
+    pub connections_active: AtomicU64,
+    pub requests_total: AtomicU64,
+    pub requests_active: AtomicU64,
+}
+
+impl ListenerStats {
+    /// Count connection as active until the guard is dropped.
+    pub fn connection(self: &Arc<Self>) -> ActiveGuard {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard::new(self.clone(), |s| &s.connections_active)
+    }
+
+    /// Count request as active until the guard is dropped.
+    pub fn request(self: &Arc<Self>) -> ActiveGuard {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard::new(self.clone(), |s| &s.requests_active)
+    }
+}
+
+/// Selects a counter of [`ListenerStats`].
+type Field = fn(&ListenerStats) -> &AtomicU64;
+
+/// Decrements an active gauge on drop, e.g. when a request is cancelled.
+pub struct ActiveGuard {
+    stats: Arc<ListenerStats>,
+    gauge: Field,
+}
+
+impl ActiveGuard {
+    fn new(stats: Arc<ListenerStats>, gauge: Field) -> Self {
+        gauge(&stats).fetch_add(1, Ordering::Relaxed);
+        Self { stats, gauge }
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        (self.gauge)(&self.stats).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct MetricsInner {
+    listeners: Vec<(String, Arc<ListenerStats>)>,
+    upstreams: Vec<(String, Pool)>,
+}
+
+/// Metrics of listeners and upstreams of the process.
+///
+/// Cloning is cheap, clones share the same metrics.
+/// Serves metrics in Prometheus text format.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<MetricsInner>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register listener with address `addr`.
+    pub fn listener(&self, addr: impl Into<String>) -> Arc<ListenerStats> {
+        let stats = Arc::new(ListenerStats::default());
+        let mut inner = self.inner.lock().unwrap();
+        inner.listeners.push((addr.into(), stats.clone()));
+        stats
+    }
+
+    /// Register upstream connection pool.
+    pub fn upstream(&self, name: impl Into<String>, pool: Pool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.upstreams.push((name.into(), pool));
+    }
+
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut text = String::new();
+        let listener_metrics: [(&str, &str, Field); 4] = [
+            ("connections_total", "counter", |s| &s.connections_total),
+            ("connections_active", "gauge", |s| &s.connections_active),
+            ("requests_total", "counter", |s| &s.requests_total),
+            ("requests_active", "gauge", |s| &s.requests_active),
+        ];
+        for (name, type_, get) in listener_metrics {
+            writeln!(text, "# TYPE llm_proxy_{name} {type_}").unwrap();
+            for (addr, stats) in &inner.listeners {
+                let value = get(stats).load(Ordering::Relaxed);
+                writeln!(
+                    text,
+                    "llm_proxy_{name}{{listener=\"{}\"}} {value}",
+                    escape_label(addr)
+                )
+                .unwrap();
+            }
+        }
+        writeln!(text, "# TYPE llm_proxy_upstream_connections_idle gauge").unwrap();
+        for (name, pool) in &inner.upstreams {
+            writeln!(
+                text,
+                "llm_proxy_upstream_connections_idle{{upstream=\"{}\"}} {}",
+                escape_label(name),
+                pool.idle_connections()
+            )
+            .unwrap();
+        }
+        text
+    }
+}
+
+impl Service for Metrics {
+    async fn call(&self, _req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+        text_response(200, "text/plain; version=0.0.4", self.render())
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[test]
+fn render_metrics() {
+    let metrics = Metrics::new();
+    let stats = metrics.listener("0.0.0.0:4000");
+    metrics.listener("unix:/run/\"admin\".sock");
+    drop((stats.request(), stats.request()));
+    let _request = stats.request();
+
+    let text = metrics.render();
+    assert!(text.contains("# TYPE llm_proxy_requests_total counter\n"));
+    assert!(text.contains("llm_proxy_requests_total{listener=\"0.0.0.0:4000\"} 3\n"));
+    assert!(text.contains("llm_proxy_requests_active{listener=\"0.0.0.0:4000\"} 1\n"));
+    assert!(
+        text.contains("llm_proxy_requests_total{listener=\"unix:/run/\\\"admin\\\".sock\"} 0\n")
+    );
+}
@@ -0,0 +1,206 @@
+use std::{borrow::Cow, fmt};
+
+use http::{Response, StatusCode, header};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+
+use crate::{Outgoing, openai::api};
+
+/// Class of error reported to the client, determines HTTP status code.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ErrorKind {
+    /// Malformed request.
+    BadRequest,
+    Unauthorized,
+    /// Authenticated, but not permitted to access the resource.
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    RateLimited,
+    /// Unexpected error in the proxy itself.
+    Internal,
+    /// Upstream server is unreachable or its response is invalid.
+    BadGateway,
+    /// Server is shutting down or upstream is overloaded.
+    Unavailable,
+    /// Upstream server didn't respond in time.
+    GatewayTimeout,
+}
+
+impl ErrorKind {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::BadGateway => StatusCode::BAD_GATEWAY,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Error `type` in OpenAI format.
+    pub fn type_(self) -> &'static str {
+        match self {
+            Self::BadRequest | Self::MethodNotAllowed | Self::PayloadTooLarge => {
+                "invalid_request_error"
+            }
+            Self::Unauthorized => "authentication_error",
+            Self::Forbidden => "permission_error",
+            Self::NotFound => "not_found_error",
+            Self::RateLimited => "rate_limit_error",
+            Self::Internal | Self::BadGateway | Self::Unavailable | Self::GatewayTimeout => {
+                "server_error"
+            }
+        }
+    }
+
+    /// Kind of error for the upstream response status.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Self::BadRequest,
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::FORBIDDEN => Self::Forbidden,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => Self::MethodNotAllowed,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited,
+            StatusCode::SERVICE_UNAVAILABLE => Self::Unavailable,
+            StatusCode::GATEWAY_TIMEOUT => Self::GatewayTimeout,
+            status if status.is_client_error() => Self::BadRequest,
+            _ => Self::BadGateway,
+        }
+    }
+}
+
+/// Error reported to the client in OpenAI format: `{"error": {"message", "type", "code"}}`.
+///
+/// Services may return it wrapped in [`anyhow::Error`], and it is converted to the response
+/// with [`ProxyError::into_response`].
+#[derive(Clone, Debug)]
+pub struct ProxyError {
+    kind: ErrorKind,
+    message: Cow<'static, str>,
+    code: Option<Cow<'static, str>>,
+}
+
+impl ProxyError {
+    pub fn new(kind: ErrorKind, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            code: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(ErrorKind::BadRequest, message)
+    }
+
+    pub fn not_found(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(ErrorKind::NotFound, message)
+    }
+
+    /// Internal error without details, which must not be exposed to the client.
+    pub fn internal() -> Self {
+        Self::new(ErrorKind::Internal, "Internal server error")
+    }
+
+    pub fn bad_gateway(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(ErrorKind::BadGateway, message)
+    }
+
+    /// Machine-readable error code, e.g. `model_not_found`.
+    pub fn code(mut self, code: impl Into<Cow<'static, str>>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn to_api(&self) -> api::ErrorResponse<'_> {
+        api::ErrorResponse {
+            error: api::ApiError {
+                message: self.message.as_ref().into(),
+                type_: self.kind.type_().into(),
+                code: self.code.as_deref().map(Into::into),
+            },
+        }
+    }
+
+    pub fn into_response(self) -> Response<Outgoing> {
+        let data = serde_json::to_vec(&self.to_api()).expect("Error serialization cannot fail");
+        let mut res = Response::new(Full::new(Bytes::from(data)).map_err(Into::into).boxed());
+        *res.status_mut() = self.kind.status();
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/json"),
+        );
+        res
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.kind.status())
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<anyhow::Error> for ProxyError {
+    /// Errors other than [`ProxyError`] are considered internal.
+    ///
+    /// Their details are logged, but not exposed to the client.
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<ProxyError>() {
+            Ok(err) => err,
+            Err(err) => {
+                log::error!("Internal error: {err:#}");
+                Self::internal()
+            }
+        }
+    }
+}
+
+#[test]
+fn error_response() {
+    let res = ProxyError::not_found("The model 'x' does not exist")
+        .code("model_not_found")
+        .into_response();
+    assert_eq!(res.status(), 404);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+
+    let err = ProxyError::from(anyhow::Error::from(ProxyError::new(
+        ErrorKind::RateLimited,
+        "slow down",
+    )));
+    assert_eq!(err.kind(), ErrorKind::RateLimited);
+    let err = ProxyError::from(anyhow::anyhow!("secret.key").context("oops"));
+    assert_eq!(err.kind().status(), 500);
+    assert_eq!(
+        serde_json::to_value(err.to_api()).unwrap(),
+        serde_json::json!({"error": {"message": "Internal server error", "type": "server_error", "code": null}})
+    );
+
+    assert_eq!(
+        ErrorKind::from_status(StatusCode::FORBIDDEN).status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        ErrorKind::from_status(StatusCode::UNAUTHORIZED).status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
@@ -1,77 +1,993 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs::Metadata,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Error;
-use http::{HeaderName, Request, Response};
-use http_body_util::{BodyExt, Empty, StreamBody};
-use hyper::body::{Frame, Incoming};
-use tokio::fs::File;
-use tokio_stream::StreamExt;
+use globset::{Glob, GlobMatcher};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, header};
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use httpdate::HttpDate;
+use hyper::body::{Bytes, Frame, Incoming};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, BufReader},
+    sync::mpsc,
+};
+use tokio_stream::{Stream, StreamExt, wrappers::ReceiverStream};
 use tokio_util::io::ReaderStream;
+use uuid::Uuid;
 
-use crate::{Outgoing, Service};
+use crate::{
+    Outgoing, Service,
+    error::{ErrorKind, ProxyError},
+    http_util::compression::{Compression, Encoding, encode},
+    request_id::RequestId,
+};
 
-fn mime(path: &Path) -> &str {
-    match path.extension().and_then(|s| s.to_str()) {
-        Some("html") => "text/html",
-        Some("js") => "text/javascript",
-        _ => "text/plain",
+/// MIME type by file extension, text types are assumed to be UTF-8.
+fn mime(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|s| s.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        // Text
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs" | "cjs") => "text/javascript; charset=utf-8",
+        Some("txt" | "log") => "text/plain; charset=utf-8",
+        Some("md" | "markdown") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml; charset=utf-8",
+        Some("json" | "map") => "application/json; charset=utf-8",
+        Some("webmanifest") => "application/manifest+json; charset=utf-8",
+        Some("svg") => "image/svg+xml; charset=utf-8",
+        // Images
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("bmp") => "image/bmp",
+        Some("ico") => "image/vnd.microsoft.icon",
+        // Fonts
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("eot") => "application/vnd.ms-fontobject",
+        // Media
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("ogg" | "oga") => "audio/ogg",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        // Other
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        _ => "application/octet-stream",
     }
 }
 
+/// Serves static files from a directory.
+///
+/// By default responses have `Content-Security-Policy` allowing only same-origin resources
+/// (and inline styles), `X-Content-Type-Options: nosniff` and `Referrer-Policy: no-referrer`.
 #[derive(Clone, Debug)]
 pub struct FileServer {
     base_path: PathBuf,
+    headers: HeaderMap,
+    cache_control: Vec<(GlobMatcher, HeaderValue)>,
+    content_etag: Vec<GlobMatcher>,
+    hashes: Arc<Mutex<HashMap<PathBuf, ContentHash>>>,
+    precompressed: bool,
+    compression: Option<Compression>,
+    spa_fallback: bool,
+    not_found_page: Option<PathBuf>,
+    error_page: Option<PathBuf>,
+}
+
+/// `ETag` made from content hash, along with file modification time and size it was computed for.
+#[derive(Clone, Debug)]
+struct ContentHash {
+    modified: Option<SystemTime>,
+    len: u64,
+    etag: HeaderValue,
 }
 
 impl FileServer {
     pub fn new(base_path: impl AsRef<Path>) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(
+                "default-src 'self'; style-src 'self' 'unsafe-inline'; img-src 'self' data:; \
+                 object-src 'none'; base-uri 'self'; frame-ancestors 'none'",
+            ),
+        );
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        headers.insert(
+            header::REFERRER_POLICY,
+            HeaderValue::from_static("no-referrer"),
+        );
         Self {
             base_path: base_path.as_ref().to_owned(),
+            headers,
+            cache_control: Vec::new(),
+            content_etag: Vec::new(),
+            hashes: Arc::default(),
+            precompressed: true,
+            compression: Some(Compression::default()),
+            spa_fallback: false,
+            not_found_page: None,
+            error_page: None,
+        }
+    }
+
+    /// Serve sibling `.br` or `.gz` file instead of the requested one when the client accepts it.
+    ///
+    /// Enabled by default.
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Compress files on the fly when there is no precompressed one, enabled by default.
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Add header to every response, replacing the default one with the same name.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Don't add the default header with this name.
+    pub fn remove_header(mut self, name: HeaderName) -> Self {
+        self.headers.remove(name);
+        self
+    }
+
+    /// `Cache-Control` for files matching `glob`, e.g. `assets/**`.
+    ///
+    /// Glob is matched against the file path relative to the root, the first matching rule applies.
+    /// Files not matching any rule are served with `Cache-Control: no-cache`,
+    /// so that clients revalidate them on each use.
+    pub fn cache_control(mut self, glob: Glob, value: HeaderValue) -> Self {
+        self.cache_control.push((glob.compile_matcher(), value));
+        self
+    }
+
+    /// Compute `ETag` from content hash for files matching `glob`, e.g. fingerprinted assets.
+    ///
+    /// For other files it is computed from size and modification time.
+    /// Hashes are cached until the file is changed.
+    pub fn content_etag(mut self, glob: Glob) -> Self {
+        self.content_etag.push(glob.compile_matcher());
+        self
+    }
+
+    async fn etag(
+        &self,
+        path: &Path,
+        relative: &Path,
+        meta: &Metadata,
+    ) -> Result<HeaderValue, Error> {
+        let modified = meta.modified().ok();
+        if !self.content_etag.iter().any(|glob| glob.is_match(relative)) {
+            let nanos = modified
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_nanos());
+            return Ok(HeaderValue::try_from(format!(
+                "\"{:x}-{nanos:x}\"",
+                meta.len()
+            ))?);
+        }
+
+        if let Some(hash) = self.hashes.lock().unwrap().get(path)
+            && (hash.modified, hash.len) == (modified, meta.len())
+        {
+            return Ok(hash.etag.clone());
+        }
+        let mut reader = BufReader::with_capacity(64 << 10, File::open(path).await?);
+        let mut hasher = Sha256::new();
+        loop {
+            let chunk = reader.fill_buf().await?;
+            if chunk.is_empty() {
+                break;
+            }
+            hasher.update(chunk);
+            let len = chunk.len();
+            reader.consume(len);
+        }
+        let hash = hasher.finalize();
+        let mut etag = String::from("\"");
+        for byte in &hash[..16] {
+            write!(etag, "{byte:02x}")?;
+        }
+        etag.push('"');
+        let etag = HeaderValue::try_from(etag)?;
+        let hash = ContentHash {
+            modified,
+            len: meta.len(),
+            etag: etag.clone(),
+        };
+        self.hashes.lock().unwrap().insert(path.to_owned(), hash);
+        Ok(etag)
+    }
+
+    /// Serve `index.html` for unknown paths without extension, used by client-side routing.
+    ///
+    /// Disabled by default.
+    pub fn spa_fallback(mut self, enabled: bool) -> Self {
+        self.spa_fallback = enabled;
+        self
+    }
+
+    /// Page served with `404` status, relative to the root, e.g. `404.html`.
+    pub fn not_found_page(mut self, page: Option<impl Into<PathBuf>>) -> Self {
+        self.not_found_page = page.map(Into::into);
+        self
+    }
+
+    /// Page served with `500` status when a file cannot be read, relative to the root.
+    pub fn error_page(mut self, page: Option<impl Into<PathBuf>>) -> Self {
+        self.error_page = page.map(Into::into);
+        self
+    }
+
+    /// File for the request path, `index.html` for directories.
+    async fn resolve(&self, mut path: &str) -> Option<PathBuf> {
+        if path.contains("/.") || path.contains("..") {
+            return None;
+        }
+        if !path.is_empty() {
+            path = path.strip_prefix("/")?;
+        }
+
+        let mut path = self.base_path.join(path);
+        let mut meta = fs::metadata(&path).await.ok()?;
+        if meta.is_dir() {
+            path = path.join("index.html");
+            meta = fs::metadata(&path).await.ok()?;
+        }
+        meta.is_file().then_some(path)
+    }
+
+    async fn fallback(&self, path: &str) -> Option<PathBuf> {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if !self.spa_fallback || name.contains('.') {
+            return None;
         }
+        let index = self.base_path.join("index.html");
+        fs::metadata(&index)
+            .await
+            .is_ok_and(|meta| meta.is_file())
+            .then_some(index)
+    }
+
+    /// Custom page for the error if configured, OpenAI error otherwise.
+    async fn render_error(&self, err: ProxyError) -> Response<Outgoing> {
+        let page = match err.kind() {
+            ErrorKind::NotFound => self.not_found_page.as_ref(),
+            ErrorKind::Internal => self.error_page.as_ref(),
+            _ => None,
+        };
+        if let Some(page) = page {
+            match fs::read(self.base_path.join(page)).await {
+                Ok(data) => {
+                    let mut res = Response::new(Full::from(data).map_err(Error::new).boxed());
+                    *res.status_mut() = err.kind().status();
+                    res.headers_mut()
+                        .insert(header::CONTENT_TYPE, HeaderValue::from_static(mime(page)));
+                    res.headers_mut()
+                        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+                    return res;
+                }
+                Err(read_err) => log::warn!("Cannot read error page {page:?}: {read_err}"),
+            }
+        }
+        err.into_response()
+    }
+
+    fn with_headers(&self, mut res: Response<Outgoing>) -> Response<Outgoing> {
+        for (name, value) in &self.headers {
+            res.headers_mut().insert(name, value.clone());
+        }
+        res
     }
 }
 
 impl Service for FileServer {
     async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
-        let path = match (|| {
-            let mut path = req.uri().path();
-            if path.contains("/.") || path.contains("..") {
-                return None;
-            }
-            if !path.is_empty() {
-                path = path.strip_prefix("/")?;
-            }
+        let id = RequestId::get(&req).map_or("-", RequestId::as_str);
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            let message = format!("Method {} is not allowed, use GET or HEAD", req.method());
+            let mut res = ProxyError::new(ErrorKind::MethodNotAllowed, message).into_response();
+            res.headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Ok(self.with_headers(res));
+        }
 
-            let mut path = self.base_path.join(path);
-            if path.is_dir() {
-                path = path.join("index.html");
-            }
-            if !path.is_file() {
-                return None;
+        let mut res = match self.serve(&req, id).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("[{id}] Cannot serve file {:?}: {err:#}", req.uri().path());
+                self.render_error(ProxyError::new(ErrorKind::Internal, "Cannot read file"))
+                    .await
             }
-            Some(path)
-        })() {
+        };
+        if req.method() == Method::HEAD {
+            *res.body_mut() = Empty::new().map_err(Error::new).boxed();
+        }
+        Ok(self.with_headers(res))
+    }
+}
+
+impl FileServer {
+    async fn serve(&self, req: &Request<Incoming>, id: &str) -> Result<Response<Outgoing>, Error> {
+        let path = match self.resolve(req.uri().path()).await {
+            Some(path) => Some(path),
+            None => self.fallback(req.uri().path()).await,
+        };
+        let path = match path {
             Some(path) => path,
             None => {
-                log::debug!("File {:?} not found", req.uri().path());
-                return Ok(Response::builder()
-                    .status(404)
-                    .body(Empty::new().map_err(Error::new).boxed())?);
+                log::debug!("[{id}] File {:?} not found", req.uri().path());
+                let message = format!("File {:?} not found", req.uri().path());
+                return Ok(self.render_error(ProxyError::not_found(message)).await);
             }
         };
 
-        log::debug!("Reading file {:?}", path);
-        let file = File::open(&path).await?;
-        let stream = ReaderStream::new(file).map(|r| match r {
+        let mime = mime(&path);
+        let accepted = Encoding::accepted(req.headers());
+        let mut precompressed = None;
+        for &encoding in accepted.iter().filter(|_| self.precompressed) {
+            let mut sibling = path.clone().into_os_string();
+            sibling.push(".");
+            sibling.push(encoding.extension());
+            if fs::metadata(&sibling)
+                .await
+                .is_ok_and(|meta| meta.is_file())
+            {
+                precompressed = Some((PathBuf::from(sibling), encoding));
+                break;
+            }
+        }
+        let (source, precompressed) = match precompressed {
+            Some((sibling, encoding)) => (sibling, Some(encoding)),
+            None => (path.clone(), None),
+        };
+
+        let file = File::open(&source).await?;
+        let meta = file.metadata().await?;
+        let relative = path.strip_prefix(&self.base_path).unwrap_or(&path);
+        let compress = match (&self.compression, precompressed) {
+            (Some(compression), None) => compression.negotiate(&accepted, mime, meta.len()),
+            _ => None,
+        };
+        let mut etag = self.etag(&source, relative, &meta).await?;
+        if let Some(encoding) = compress {
+            // Compressed content is a different representation
+            etag = HeaderValue::try_from(format!(
+                "{}-{}\"",
+                etag.to_str()?.trim_end_matches('"'),
+                encoding.name()
+            ))?;
+        }
+        // HTTP dates have a precision of one second
+        let modified = meta.modified().ok().map(HttpDate::from);
+
+        let mut builder = Response::builder().header(header::ETAG, &etag).header(
+            header::CACHE_CONTROL,
+            self.cache_control
+                .iter()
+                .find(|(glob, _)| glob.is_match(relative))
+                .map_or(HeaderValue::from_static("no-cache"), |(_, value)| {
+                    value.clone()
+                }),
+        );
+        if let Some(modified) = modified {
+            builder = builder.header(header::LAST_MODIFIED, modified.to_string());
+        }
+        if self.precompressed || self.compression.is_some() {
+            builder = builder.header(header::VARY, "accept-encoding");
+        }
+        if let Some(encoding) = precompressed.or(compress) {
+            builder = builder.header(header::CONTENT_ENCODING, encoding.name());
+        }
+
+        if not_modified(req.headers(), &etag, modified) {
+            log::debug!("[{id}] File {:?} not modified", path);
+            let res = builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Empty::new().map_err(Error::new).boxed())?;
+            return Ok(res);
+        }
+
+        let len = meta.len();
+        // Ranges of content compressed on the fly are not supported
+        let ranges = match req.headers().get(header::RANGE) {
+            Some(range) if compress.is_none() && if_range(req.headers(), &etag, modified) => range
+                .to_str()
+                .map_or(Ranges::Full, |range| Ranges::parse(range, len)),
+            _ => Ranges::Full,
+        };
+        if compress.is_none() {
+            builder = builder.header(header::ACCEPT_RANGES, "bytes");
+        }
+        let (builder, stream) = match ranges {
+            Ranges::Full => {
+                log::debug!("[{id}] Reading file {:?}", source);
+                let stream: FileStream = Box::pin(ReaderStream::new(file));
+                let mut builder = builder
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, mime);
+                if compress.is_none() {
+                    builder = builder.header(header::CONTENT_LENGTH, len);
+                }
+                (builder, stream)
+            }
+            Ranges::Unsatisfiable => {
+                log::debug!("[{id}] Range of file {:?} is not satisfiable", path);
+                let res = builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+                    .body(Empty::new().map_err(Error::new).boxed())?;
+                return Ok(res);
+            }
+            Ranges::Parts(parts) if parts.len() == 1 => {
+                let (start, end) = parts[0];
+                log::debug!("[{id}] Reading bytes {start}..{end} of file {:?}", path);
+                let builder = builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_TYPE, mime)
+                    .header(header::CONTENT_LENGTH, end - start)
+                    .header(
+                        header::CONTENT_RANGE,
+                        format!("bytes {start}-{}/{len}", end - 1),
+                    );
+                (builder, read_range(file, start, end).await?)
+            }
+            Ranges::Parts(parts) => {
+                log::debug!("[{id}] Reading {} ranges of file {:?}", parts.len(), path);
+                let boundary = Uuid::new_v4().simple().to_string();
+                let mut length = 0;
+                let mut sections = Vec::with_capacity(parts.len());
+                for (start, end) in parts {
+                    let head = format!(
+                        "\r\n--{boundary}\r\n{}: {mime}\r\n{}: bytes {start}-{}/{len}\r\n\r\n",
+                        header::CONTENT_TYPE,
+                        header::CONTENT_RANGE,
+                        end - 1,
+                    );
+                    length += head.len() as u64 + (end - start);
+                    sections.push((Bytes::from(head), start, end));
+                }
+                let tail = format!("\r\n--{boundary}--\r\n");
+                length += tail.len() as u64;
+                let stream = read_multipart(file, sections, Bytes::from(tail));
+                let builder = builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(
+                        header::CONTENT_TYPE,
+                        format!("multipart/byteranges; boundary={boundary}"),
+                    )
+                    .header(header::CONTENT_LENGTH, length);
+                (builder, stream)
+            }
+        };
+        let stream = stream.map(|r| match r {
             Ok(x) => Ok(Frame::data(x)),
             Err(e) => Err(Error::from(e)),
         });
+        let mut body = StreamBody::new(stream).boxed();
+        if let Some(encoding) = compress {
+            body = encode(body, encoding);
+        }
+        Ok(builder.body(body)?)
+    }
+}
+
+type FileStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + Sync>>;
+
+/// Stream of file bytes in `start..end`.
+async fn read_range(mut file: File, start: u64, end: u64) -> io::Result<FileStream> {
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(Box::pin(ReaderStream::new(file.take(end - start))))
+}
+
+/// Stream of multipart body, ranges `start..end` preceded by their headers are read using a single file handle.
+fn read_multipart(mut file: File, sections: Vec<(Bytes, u64, u64)>, tail: Bytes) -> FileStream {
+    let (send, recv) = mpsc::channel(2);
+    tokio::task::spawn(async move {
+        for (head, start, end) in sections {
+            if send.send(Ok(head)).await.is_err() {
+                return;
+            }
+            if let Err(err) = file.seek(SeekFrom::Start(start)).await {
+                let _ = send.send(Err(err)).await;
+                return;
+            }
+            let mut part = ReaderStream::new((&mut file).take(end - start));
+            while let Some(chunk) = part.next().await {
+                let failed = chunk.is_err();
+                // Client is gone or the file can't be read
+                if send.send(chunk).await.is_err() || failed {
+                    return;
+                }
+            }
+        }
+        let _ = send.send(Ok(tail)).await;
+    });
+    Box::pin(ReceiverStream::new(recv))
+}
+
+/// Byte ranges requested by the `Range` header.
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Ranges {
+    /// No valid `Range` header, the whole file is served.
+    Full,
+    /// None of the ranges overlap the file.
+    Unsatisfiable,
+    /// Ranges in `start..end` form, sorted with overlapping and adjacent ones merged.
+    Parts(Vec<(u64, u64)>),
+}
 
-        let res = Response::builder()
-            .status(200)
-            .header(HeaderName::from_static("content-type"), mime(&path))
-            .body(StreamBody::new(stream).boxed())?;
+impl Ranges {
+    /// More ranges are considered an abuse and the whole file is served instead.
+    const MAX_PARTS: usize = 16;
 
-        Ok(res)
+    fn parse(value: &str, len: u64) -> Self {
+        let Some(specs) = value.trim().strip_prefix("bytes=") else {
+            return Self::Full;
+        };
+        // Empty position is allowed
+        let position = |s: &str| match s.trim() {
+            "" => Ok(None),
+            s => s.parse::<u64>().map(Some),
+        };
+        let mut parts = Vec::new();
+        let mut valid = false;
+        for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let Some((start, end)) = spec.split_once('-') else {
+                return Self::Full;
+            };
+            let (Ok(start), Ok(end)) = (position(start), position(end)) else {
+                return Self::Full;
+            };
+            let (start, end) = match (start, end) {
+                (Some(start), Some(end)) if start <= end => (start, end.saturating_add(1).min(len)),
+                (Some(start), None) => (start, len),
+                // Last `n` bytes
+                (None, Some(n)) => (len.saturating_sub(n), if n == 0 { 0 } else { len }),
+                _ => return Self::Full,
+            };
+            valid = true;
+            if start < end {
+                parts.push((start, end));
+            }
+        }
+        if !valid || parts.len() > Self::MAX_PARTS {
+            return Self::Full;
+        }
+        parts.sort_unstable();
+        parts.dedup_by(|(start, end), (_, prev_end)| {
+            if *start <= *prev_end {
+                *prev_end = (*prev_end).max(*end);
+                true
+            } else {
+                false
+            }
+        });
+        if parts.is_empty() {
+            Self::Unsatisfiable
+        } else {
+            Self::Parts(parts)
+        }
+    }
+}
+
+/// Whether `Range` should be applied according to `If-Range` precondition.
+fn if_range(headers: &HeaderMap, etag: &HeaderValue, modified: Option<HttpDate>) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    if value.as_bytes().starts_with(b"\"") {
+        // Strong comparison
+        value == etag
+    } else {
+        value
+            .to_str()
+            .ok()
+            .and_then(|date| date.parse::<HttpDate>().ok())
+            .is_some_and(|date| Some(date) == modified)
+    }
+}
+
+/// Whether the client already has the current version of the file.
+///
+/// `If-Modified-Since` is ignored when `If-None-Match` is present.
+fn not_modified(headers: &HeaderMap, etag: &HeaderValue, modified: Option<HttpDate>) -> bool {
+    if let Some(tags) = headers.get(header::IF_NONE_MATCH) {
+        let (Ok(tags), Ok(etag)) = (tags.to_str(), etag.to_str()) else {
+            return false;
+        };
+        // Weak comparison
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
     }
+    match (headers.get(header::IF_MODIFIED_SINCE), modified) {
+        (Some(since), Some(modified)) => since
+            .to_str()
+            .ok()
+            .and_then(|since| since.parse::<HttpDate>().ok())
+            .is_some_and(|since| modified <= since),
+        _ => false,
+    }
+}
+
+#[tokio::test]
+async fn file_headers() {
+    use crate::{server::test_request, test_util::TempDir};
+
+    let root = TempDir::new("files");
+    root.write("index.html", "<html></html>");
+    root.write("assets/style.CSS", "body {}");
+    root.write("assets/app.wasm", "\0asm");
+
+    let files = Arc::new(
+        FileServer::new(root.path())
+            .remove_header(header::REFERRER_POLICY)
+            .header(
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static("default-src 'none'"),
+            )
+            .cache_control(
+                Glob::new("assets/**").unwrap(),
+                HeaderValue::from_static("max-age=3600"),
+            )
+            .cache_control(
+                Glob::new("*").unwrap(),
+                HeaderValue::from_static("no-cache"),
+            ),
+    );
+    let get = async |path: &str| {
+        let req = Request::get(path).body(Full::<Bytes>::default()).unwrap();
+        test_request(files.clone(), req).await
+    };
+
+    let res = get("/").await;
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(res.headers()[header::CACHE_CONTROL], "no-cache");
+    assert_eq!(
+        res.headers()[header::CONTENT_SECURITY_POLICY],
+        "default-src 'none'"
+    );
+    assert_eq!(res.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert!(!res.headers().contains_key(header::REFERRER_POLICY));
+
+    let res = get("/assets/style.CSS").await;
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "text/css; charset=utf-8"
+    );
+    assert_eq!(res.headers()[header::CACHE_CONTROL], "max-age=3600");
+    let res = get("/assets/app.wasm").await;
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "application/wasm");
+
+    let res = get("/missing").await;
+    assert_eq!(res.status(), 404);
+    assert_eq!(res.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+}
+
+#[tokio::test]
+async fn conditional_get() {
+    use crate::{server::test_request, test_util::TempDir};
+
+    let root = TempDir::new("etag");
+    root.write("index.html", "<html></html>");
+    root.write("main.abc123.js", "let a;");
+
+    let files = Arc::new(FileServer::new(root.path()).content_etag(Glob::new("*.*.js").unwrap()));
+    let get = async |path: &str, headers: &[(HeaderName, &str)]| {
+        let mut req = Request::get(path).body(Full::<Bytes>::default()).unwrap();
+        for (name, value) in headers {
+            req.headers_mut()
+                .insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        test_request(files.clone(), req).await
+    };
+
+    let res = get("/", &[]).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "13");
+    assert_eq!(res.headers()[header::CACHE_CONTROL], "no-cache");
+    let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+    let modified = res.headers()[header::LAST_MODIFIED]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let res = get("/", &[(header::IF_NONE_MATCH, &format!("\"x\", W/{etag}"))]).await;
+    assert_eq!(res.status(), 304);
+    assert!(res.body().is_empty());
+    assert_eq!(res.headers()[header::ETAG], etag.as_str());
+    let res = get("/", &[(header::IF_MODIFIED_SINCE, &modified)]).await;
+    assert_eq!(res.status(), 304);
+    let res = get(
+        "/",
+        &[(header::IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    // `If-None-Match` takes precedence
+    let headers = [
+        (header::IF_NONE_MATCH, "\"x\""),
+        (header::IF_MODIFIED_SINCE, &modified),
+    ];
+    assert_eq!(get("/", &headers).await.status(), 200);
+
+    // Content hash is recomputed when the file changes
+    let etag = get("/main.abc123.js", &[]).await.headers()[header::ETAG].clone();
+    assert_eq!(etag.len(), 34);
+    root.write("main.abc123.js", "let b = 1;");
+    assert_ne!(
+        get("/main.abc123.js", &[]).await.headers()[header::ETAG],
+        etag
+    );
+}
+
+#[test]
+fn parse_ranges() {
+    assert_eq!(
+        Ranges::parse("bytes=0-9", 100),
+        Ranges::Parts(vec![(0, 10)])
+    );
+    assert_eq!(
+        Ranges::parse("bytes=90-", 100),
+        Ranges::Parts(vec![(90, 100)])
+    );
+    assert_eq!(
+        Ranges::parse("bytes=-10", 100),
+        Ranges::Parts(vec![(90, 100)])
+    );
+    assert_eq!(
+        Ranges::parse("bytes=-200", 100),
+        Ranges::Parts(vec![(0, 100)])
+    );
+    assert_eq!(
+        Ranges::parse("bytes=50-200", 100),
+        Ranges::Parts(vec![(50, 100)])
+    );
+    assert_eq!(
+        Ranges::parse("bytes=0-0, 200-, -1", 100),
+        Ranges::Parts(vec![(0, 1), (99, 100)])
+    );
+    assert_eq!(
+        Ranges::parse("bytes=50-59, 0-4, 2-6, 7-9, 55-", 100),
+        Ranges::Parts(vec![(0, 10), (50, 100)])
+    );
+    assert_eq!(Ranges::parse("bytes=100-", 100), Ranges::Unsatisfiable);
+    assert_eq!(Ranges::parse("bytes=-0", 100), Ranges::Unsatisfiable);
+    assert_eq!(Ranges::parse("bytes=0-", 0), Ranges::Unsatisfiable);
+    assert_eq!(Ranges::parse("bytes=5-1", 100), Ranges::Full);
+    assert_eq!(Ranges::parse("bytes=x-1", 100), Ranges::Full);
+    assert_eq!(Ranges::parse("items=0-1", 100), Ranges::Full);
+    assert_eq!(Ranges::parse("bytes=", 100), Ranges::Full);
+    let many = format!("bytes={}", vec!["0-0"; 17].join(","));
+    assert_eq!(Ranges::parse(&many, 100), Ranges::Full);
+}
+
+#[tokio::test]
+async fn range_requests() {
+    use crate::{server::test_request, test_util::TempDir};
+
+    let root = TempDir::new("range");
+    root.write("data.txt", "0123456789");
+
+    let files = Arc::new(FileServer::new(root.path()));
+    let get = async |headers: &[(HeaderName, &str)]| {
+        let mut req = Request::get("/data.txt")
+            .body(Full::<Bytes>::default())
+            .unwrap();
+        for (name, value) in headers {
+            req.headers_mut()
+                .insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        test_request(files.clone(), req).await
+    };
+
+    let res = get(&[]).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()[header::ACCEPT_RANGES], "bytes");
+    let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+
+    let res = get(&[(header::RANGE, "bytes=2-4")]).await;
+    assert_eq!(res.status(), 206);
+    assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "3");
+    assert_eq!(res.body(), "234");
+
+    let res = get(&[(header::RANGE, "bytes=20-")]).await;
+    assert_eq!(res.status(), 416);
+    assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */10");
+
+    let res = get(&[(header::RANGE, "bytes=0-1,-2")]).await;
+    assert_eq!(res.status(), 206);
+    let content_type = res.headers()[header::CONTENT_TYPE].to_str().unwrap();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+    let expected = format!(
+        "\r\n--{boundary}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-range: bytes 0-1/10\r\n\r\n01\
+         \r\n--{boundary}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-range: bytes 8-9/10\r\n\r\n89\
+         \r\n--{boundary}--\r\n"
+    );
+    assert_eq!(res.body(), expected.as_bytes());
+    assert_eq!(
+        res.headers()[header::CONTENT_LENGTH],
+        expected.len().to_string().as_str()
+    );
+
+    // Overlapping ranges are merged
+    let res = get(&[(header::RANGE, "bytes=0-3,2-5")]).await;
+    assert_eq!(res.status(), 206);
+    assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 0-5/10");
+    assert_eq!(res.body(), "012345");
+
+    // Range is ignored if the file has changed
+    let res = get(&[(header::RANGE, "bytes=0-1"), (header::IF_RANGE, "\"old\"")]).await;
+    assert_eq!(res.status(), 200);
+    let res = get(&[(header::RANGE, "bytes=0-1"), (header::IF_RANGE, &etag)]).await;
+    assert_eq!(res.status(), 206);
+}
+
+#[tokio::test]
+async fn compressed_files() {
+    use crate::{server::test_request, test_util::TempDir};
+    use async_compression::tokio::bufread::GzipDecoder;
+
+    let root = TempDir::new("gzip");
+    let script = "console.log('hello');\n".repeat(100);
+    root.write("app.js", &script);
+    root.write("app.js.br", "brotli");
+    root.write("small.js", "let a;");
+
+    let files = Arc::new(FileServer::new(root.path()));
+    let get = async |path: &str, encoding: &str| {
+        let req = Request::get(path)
+            .header(header::ACCEPT_ENCODING, encoding)
+            .body(Full::<Bytes>::default())
+            .unwrap();
+        test_request(files.clone(), req).await
+    };
+
+    // Precompressed file is preferred
+    let res = get("/app.js", "gzip, br").await;
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "text/javascript; charset=utf-8"
+    );
+    assert_eq!(res.headers()[header::VARY], "accept-encoding");
+    assert_eq!(res.body(), "brotli");
+
+    let res = get("/app.js", "gzip").await;
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    assert!(!res.headers().contains_key(header::CONTENT_LENGTH));
+    assert!(!res.headers().contains_key(header::ACCEPT_RANGES));
+    assert!(
+        res.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .ends_with("-gzip\"")
+    );
+    let mut data = String::new();
+    GzipDecoder::new(res.body().as_ref())
+        .read_to_string(&mut data)
+        .await
+        .unwrap();
+    assert_eq!(data, script);
+
+    let res = get("/small.js", "gzip").await;
+    assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(res.body(), "let a;");
+    let res = get("/app.js", "identity").await;
+    assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(res.body(), script.as_str());
+}
+
+#[tokio::test]
+async fn spa_and_error_pages() {
+    use crate::{server::test_request, test_util::TempDir};
+
+    let root = TempDir::new("spa");
+    root.write("index.html", "<app>");
+    root.write("404.html", "<missing>");
+
+    let files = Arc::new(
+        FileServer::new(root.path())
+            .spa_fallback(true)
+            .not_found_page(Some("404.html")),
+    );
+    let request = async |method: Method, path: &str| {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Full::<Bytes>::default())
+            .unwrap();
+        test_request(files.clone(), req).await
+    };
+
+    // Client-side route
+    let res = request(Method::GET, "/chats/42").await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body(), "<app>");
+    // Missing asset
+    let res = request(Method::GET, "/build/main.js").await;
+    assert_eq!(res.status(), 404);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(res.body(), "<missing>");
+
+    let res = request(Method::HEAD, "/index.html").await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "5");
+    assert!(res.body().is_empty());
+
+    let res = request(Method::POST, "/index.html").await;
+    assert_eq!(res.status(), 405);
+    assert_eq!(res.headers()[header::ALLOW], "GET, HEAD");
+}
+
+/// Reading `/proc/self/mem` at offset 0 fails, which relies on Linux procfs.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn error_page() {
+    use crate::{server::test_request, test_util::TempDir};
+
+    let root = TempDir::new("error-page");
+    root.write("500.html", "<broken>");
+    std::os::unix::fs::symlink("/proc/self/mem", root.path().join("broken.bin")).unwrap();
+
+    let files = Arc::new(
+        FileServer::new(root.path())
+            .content_etag(Glob::new("*.bin").unwrap())
+            .error_page(Some("500.html")),
+    );
+    let req = Request::get("/broken.bin")
+        .body(Full::<Bytes>::default())
+        .unwrap();
+    let res = test_request(files, req).await;
+    assert_eq!(res.status(), 500);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(res.headers()[header::CACHE_CONTROL], "no-store");
+    assert_eq!(res.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(res.body(), "<broken>");
 }
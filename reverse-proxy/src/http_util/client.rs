@@ -0,0 +1,526 @@
+use std::{path::PathBuf, pin::Pin, sync::Arc};
+
+use anyhow::{Error, anyhow, bail};
+use http::{Version, header};
+use http_body_util::Full;
+use hyper::{
+    Request, Response, Uri,
+    body::{Bytes, Incoming},
+    client::conn::{TrySendError, http1, http2},
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use openssl::ssl::SslConnector;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+    sync::OnceCell,
+    task::JoinHandle,
+};
+use tokio_openssl::SslStream;
+
+use crate::{http_util, listener::UNIX_PREFIX, tls::ClientTlsConfig};
+
+/// ALPN protocol list in wire format.
+const ALPN_H2_HTTP1: &[u8] = b"\x02h2\x08http/1.1";
+
+pub fn url_to_host_and_port(url: &Uri) -> Result<(&str, u16), Error> {
+    // Get the host and the port
+    let host = url
+        .host()
+        .ok_or_else(|| anyhow!("URL has no host: {url}"))?;
+    let port = url.port_u16();
+    match url
+        .scheme_str()
+        .ok_or_else(|| anyhow!("Server address has no scheme: {url}"))?
+    {
+        "http" => Ok((host, port.unwrap_or(80))),
+        "https" => Ok((host, port.unwrap_or(443))),
+        scheme => Err(anyhow!("Unsupported scheme: {scheme}")),
+    }
+}
+
+/// Parse upstream server address.
+///
+/// Address is either URL like `https://host:port/` or Unix domain socket path like `unix:/run/llama.sock`.
+/// Requests to Unix domain socket are sent via HTTP with `host` (`localhost` by default) in the `Host` header.
+pub fn parse_upstream(addr: &str, host: Option<&str>) -> Result<(Uri, Option<PathBuf>), Error> {
+    match addr.strip_prefix(UNIX_PREFIX) {
+        Some(path) => {
+            let url = format!("http://{}/", host.unwrap_or("localhost"));
+            let url = url
+                .parse()
+                .map_err(|err| anyhow!("Invalid upstream host {host:?}: {err}"))?;
+            Ok((url, Some(path.into())))
+        }
+        None if host.is_some() => {
+            bail!("Host can be overridden only for Unix domain socket upstream")
+        }
+        None => Ok((addr.parse()?, None)),
+    }
+}
+
+/// Single HTTP connection to the upstream server.
+///
+/// HTTP/2 connections can be cloned to send multiple requests concurrently.
+pub struct Connection {
+    sender: Sender,
+    url: Uri,
+    task: Arc<JoinHandle<()>>,
+}
+
+enum Sender {
+    Http1(http1::SendRequest<Full<Bytes>>),
+    Http2(http2::SendRequest<Full<Bytes>>),
+}
+
+impl Connection {
+    async fn handshake<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        stream: S,
+        url: &Uri,
+        version: Version,
+    ) -> Result<Self, Error> {
+        let (host, port) = url_to_host_and_port(url)?;
+        let addr = format!("{host}:{port}");
+
+        // Use an adapter to access something implementing `tokio::io` traits as if they implement
+        // `hyper::rt` IO traits.
+        let io = TokioIo::new(stream);
+
+        // Create the Hyper client and spawn a task to poll the connection, driving the HTTP state
+        let (sender, task) = if version == Version::HTTP_2 {
+            let (sender, conn) = http2::handshake(TokioExecutor::new(), io).await?;
+            (Sender::Http2(sender), spawn_connection(conn, addr, version))
+        } else {
+            let (sender, conn) = http1::handshake(io).await?;
+            (Sender::Http1(sender), spawn_connection(conn, addr, version))
+        };
+
+        Ok(Self {
+            sender,
+            url: url.clone(),
+            task: Arc::new(task),
+        })
+    }
+
+    pub fn version(&self) -> Version {
+        match &self.sender {
+            Sender::Http1(_) => Version::HTTP_11,
+            Sender::Http2(_) => Version::HTTP_2,
+        }
+    }
+
+    /// Whether multiple requests can be sent over the connection concurrently.
+    pub fn is_multiplexed(&self) -> bool {
+        matches!(self.sender, Sender::Http2(_))
+    }
+
+    /// Get another handle to the same connection if it supports multiplexing.
+    pub fn try_clone(&self) -> Option<Self> {
+        match &self.sender {
+            Sender::Http1(_) => None,
+            Sender::Http2(sender) => Some(Self {
+                sender: Sender::Http2(sender.clone()),
+                url: self.url.clone(),
+                task: self.task.clone(),
+            }),
+        }
+    }
+
+    pub async fn send(
+        &mut self,
+        req: Request<Full<Bytes>>,
+    ) -> Result<Response<Incoming>, hyper::Error> {
+        self.try_send(req).await.map_err(TrySendError::into_error)
+    }
+
+    /// Send request, the error gives the request back if it failed before the request was written.
+    pub async fn try_send(
+        &mut self,
+        mut req: Request<Full<Bytes>>,
+    ) -> Result<Response<Incoming>, TrySendError<Request<Full<Bytes>>>> {
+        match &mut self.sender {
+            Sender::Http1(sender) => sender.try_send_request(req).await,
+            Sender::Http2(sender) => {
+                // HTTP/2 requires scheme and authority to be passed in the request URI.
+                let mut parts = req.uri().clone().into_parts();
+                parts.scheme = self.url.scheme().cloned();
+                parts.authority = self.url.authority().cloned();
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+                *req.version_mut() = Version::HTTP_2;
+                req.headers_mut().remove(header::HOST);
+                sender.try_send_request(req).await
+            }
+        }
+    }
+
+    /// Wait until the connection is able to send the next request.
+    ///
+    /// Fails if the connection is closed in the meantime.
+    pub async fn ready(&mut self) -> Result<(), hyper::Error> {
+        match &mut self.sender {
+            Sender::Http1(sender) => sender.ready().await,
+            Sender::Http2(sender) => sender.ready().await,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let closed = match &self.sender {
+            Sender::Http1(sender) => sender.is_closed(),
+            Sender::Http2(sender) => sender.is_closed(),
+        };
+        closed || self.task.is_finished()
+    }
+}
+
+fn spawn_connection<C>(conn: C, addr: String, version: Version) -> JoinHandle<()>
+where
+    C: Future<Output = Result<(), hyper::Error>> + Send + 'static,
+{
+    log::debug!("Outgoing {version:?} connection to {addr} established");
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await {
+            log::error!("Outgoing connection to {addr} failed: {:?}", err);
+        } else {
+            log::debug!("Outgoing connection to {addr} closed");
+        }
+    })
+}
+
+/// Knows how to establish a new connection to the upstream server.
+#[derive(Clone, Debug)]
+pub struct Connector {
+    url: Uri,
+    proxy: Option<Uri>,
+    http2: bool,
+    tls: ClientTlsConfig,
+    /// OpenSSL connector built from `tls` on the first TLS connection.
+    ssl: Arc<OnceCell<SslConnector>>,
+    /// Connect to Unix domain socket instead of `url` host and port.
+    unix_socket: Option<PathBuf>,
+}
+
+impl Connector {
+    pub fn new(url: Uri) -> Self {
+        Self {
+            url,
+            proxy: None,
+            http2: true,
+            tls: ClientTlsConfig::default(),
+            ssl: Arc::default(),
+            unix_socket: None,
+        }
+    }
+
+    pub fn proxy(mut self, proxy: Option<Uri>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Offer HTTP/2 via ALPN when connecting over TLS.
+    ///
+    /// HTTP/1.1 is used if the server doesn't support HTTP/2.
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.http2 = enabled;
+        self.ssl = Arc::default();
+        self
+    }
+
+    pub fn tls(mut self, config: ClientTlsConfig) -> Self {
+        self.tls = config;
+        self.ssl = Arc::default();
+        self
+    }
+
+    /// Connect to Unix domain socket at `path`.
+    ///
+    /// `url` is still used for `Host` header and for choosing between HTTP and HTTPS.
+    pub fn unix_socket(mut self, path: Option<PathBuf>) -> Self {
+        self.unix_socket = path;
+        self
+    }
+
+    pub fn url(&self) -> &Uri {
+        &self.url
+    }
+
+    pub async fn connect(&self) -> Result<Connection, Error> {
+        if let Some(path) = &self.unix_socket {
+            if self.proxy.is_some() {
+                bail!("HTTP proxy cannot be used to connect to Unix domain socket");
+            }
+            let stream = UnixStream::connect(path).await?;
+            return self.connect_raw_socket(stream).await;
+        }
+        let stream = match &self.proxy {
+            // Open a TCP connection to the remote host
+            None => TcpStream::connect(url_to_host_and_port(&self.url)?).await?,
+            Some(proxy) => {
+                let mut stream = TcpStream::connect(url_to_host_and_port(proxy)?).await?;
+                http_util::proxy::handshake(&mut stream, url_to_host_and_port(&self.url)?).await?;
+                stream
+            }
+        };
+        self.connect_raw_socket(stream).await
+    }
+
+    async fn connect_raw_socket<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        &self,
+        stream: S,
+    ) -> Result<Connection, Error> {
+        let url = &self.url;
+        match url.scheme_str().expect("Server address has no scheme") {
+            "http" => Connection::handshake(stream, url, Version::HTTP_11).await,
+            "https" => {
+                let connector = self
+                    .ssl
+                    .get_or_try_init(async || {
+                        self.tls.build(if self.http2 {
+                            Some(ALPN_H2_HTTP1)
+                        } else {
+                            None
+                        })
+                    })
+                    .await?;
+                let mut config = connector.configure()?;
+                if self.tls.is_insecure() {
+                    config.set_verify_hostname(false);
+                }
+                // Sets SNI and hostname to verify
+                let ssl = config.into_ssl(url_to_host_and_port(url)?.0)?;
+                let mut ssl_stream = SslStream::new(ssl, stream)?;
+                Pin::new(&mut ssl_stream).connect().await?;
+
+                let version = match ssl_stream.ssl().selected_alpn_protocol() {
+                    Some(b"h2") => Version::HTTP_2,
+                    _ => Version::HTTP_11,
+                };
+                Connection::handshake(ssl_stream, url, version).await
+            }
+            scheme => bail!("Unsupported scheme: {scheme}"),
+        }
+    }
+}
+
+/// Spawn HTTPS server that accepts HTTP/1.1 and HTTP/2 and responds with `ok`.
+///
+/// Returns the server URL and the number of accepted connections.
+#[cfg(test)]
+async fn test_tls_server(
+    cert: &crate::tls::test_certs::Cert,
+    client_ca: Option<&crate::tls::test_certs::Cert>,
+) -> (Uri, Arc<std::sync::atomic::AtomicUsize>) {
+    use hyper_util::server::conn::auto;
+    use openssl::ssl::{AlpnError, Ssl, SslAcceptor, SslMethod, SslVerifyMode, select_next_proto};
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert.cert).unwrap();
+    acceptor.set_private_key(&cert.key).unwrap();
+    acceptor.set_alpn_select_callback(|_, client| {
+        select_next_proto(ALPN_H2_HTTP1, client).ok_or(AlpnError::NOACK)
+    });
+    if let Some(ca) = client_ca {
+        acceptor.cert_store_mut().add_cert(ca.cert.clone()).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    let acceptor = acceptor.build();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!(
+        "https://localhost:{}/",
+        listener.local_addr().unwrap().port()
+    );
+    let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let accepted = connections.clone();
+    tokio::task::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let ssl = Ssl::new(acceptor.context()).unwrap();
+            let mut stream = SslStream::new(ssl, stream).unwrap();
+            tokio::task::spawn(async move {
+                if Pin::new(&mut stream).accept().await.is_err() {
+                    return;
+                }
+                let service = hyper::service::service_fn(|_| async {
+                    Ok::<_, std::convert::Infallible>(Response::new(Full::new(Bytes::from("ok"))))
+                });
+                let _ = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    (url.parse().unwrap(), connections)
+}
+
+#[cfg(test)]
+async fn test_get(conn: &mut Connection) -> Result<Bytes, Error> {
+    use http_body_util::BodyExt;
+
+    let req = Request::builder()
+        .uri("/")
+        .header(header::HOST, conn.url.authority().unwrap().as_str())
+        .body(Full::new(Bytes::new()))?;
+    let res = conn.send(req).await?;
+    Ok(res.into_body().collect().await?.to_bytes())
+}
+
+#[tokio::test]
+async fn tls_verify() {
+    use crate::tls::test_certs;
+
+    let ca = test_certs::ca("Test CA");
+    let (url, _) =
+        test_tls_server(&test_certs::signed(&ca, "localhost", &["localhost"]), None).await;
+
+    // Unknown CA
+    assert!(Connector::new(url.clone()).connect().await.is_err());
+
+    let tls = ClientTlsConfig::default().ca_file(Some(ca.cert_file()));
+    let mut conn = Connector::new(url.clone())
+        .tls(tls)
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(test_get(&mut conn).await.unwrap(), "ok");
+
+    let tls = ClientTlsConfig::default().insecure(true);
+    let mut conn = Connector::new(url).tls(tls).connect().await.unwrap();
+    assert_eq!(test_get(&mut conn).await.unwrap(), "ok");
+}
+
+#[tokio::test]
+async fn tls_hostname_mismatch() {
+    use crate::tls::test_certs;
+
+    let ca = test_certs::ca("Test CA");
+    let (url, _) =
+        test_tls_server(&test_certs::signed(&ca, "example", &["example.com"]), None).await;
+
+    let tls = ClientTlsConfig::default().ca_file(Some(ca.cert_file()));
+    assert!(
+        Connector::new(url.clone())
+            .tls(tls)
+            .connect()
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn tls_client_cert() {
+    use crate::tls::test_certs;
+
+    let ca = test_certs::ca("Test CA");
+    let client_ca = test_certs::ca("Test client CA");
+    let server_cert = test_certs::signed(&ca, "localhost", &["localhost"]);
+    let (url, _) = test_tls_server(&server_cert, Some(&client_ca)).await;
+
+    let tls = ClientTlsConfig::default().ca_file(Some(ca.cert_file()));
+    let res = async {
+        // TLS 1.3 client certificate is verified by server after handshake.
+        let mut conn = Connector::new(url.clone())
+            .tls(tls.clone())
+            .connect()
+            .await?;
+        test_get(&mut conn).await
+    };
+    assert!(res.await.is_err());
+
+    let client_cert = test_certs::signed(&client_ca, "client", &[]);
+    let tls = tls.client_cert(Some((client_cert.cert_file(), client_cert.key_file())));
+    let mut conn = Connector::new(url).tls(tls).connect().await.unwrap();
+    assert_eq!(test_get(&mut conn).await.unwrap(), "ok");
+}
+
+#[tokio::test]
+async fn alpn_http2() {
+    use crate::tls::test_certs;
+
+    let ca = test_certs::ca("Test CA");
+    let (url, _) =
+        test_tls_server(&test_certs::signed(&ca, "localhost", &["localhost"]), None).await;
+    let tls = ClientTlsConfig::default().ca_file(Some(ca.cert_file()));
+
+    let mut conn = Connector::new(url.clone())
+        .tls(tls.clone())
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(conn.version(), Version::HTTP_2);
+    assert!(conn.is_multiplexed());
+    assert_eq!(test_get(&mut conn).await.unwrap(), "ok");
+
+    let mut conn = Connector::new(url)
+        .tls(tls)
+        .http2(false)
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(conn.version(), Version::HTTP_11);
+    assert!(conn.try_clone().is_none());
+    assert_eq!(test_get(&mut conn).await.unwrap(), "ok");
+}
+
+#[tokio::test]
+async fn multiplex() {
+    use std::sync::atomic::Ordering;
+
+    use http_body_util::BodyExt;
+
+    use super::pool::{Pool, PoolConfig};
+    use crate::tls::test_certs;
+
+    let ca = test_certs::ca("Test CA");
+    let (url, connections) =
+        test_tls_server(&test_certs::signed(&ca, "localhost", &["localhost"]), None).await;
+    let tls = ClientTlsConfig::default().ca_file(Some(ca.cert_file()));
+    let pool = Pool::new(Connector::new(url.clone()).tls(tls), PoolConfig::default());
+    let send = || {
+        let req = Request::builder()
+            .uri("/")
+            .header(header::HOST, url.authority().unwrap().as_str())
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let pool = pool.clone();
+        async move {
+            let res = pool.send(req).await.unwrap();
+            assert_eq!(res.version(), Version::HTTP_2);
+            res.into_body().collect().await.unwrap().to_bytes()
+        }
+    };
+
+    assert_eq!(send().await, "ok");
+    let responses = tokio::task::JoinSet::from_iter((0..10).map(|_| send()));
+    for body in responses.join_all().await {
+        assert_eq!(body, "ok");
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn upstream_addresses() {
+    let (url, socket) = parse_upstream("https://example.com:8443/", None).unwrap();
+    assert_eq!(url, "https://example.com:8443/");
+    assert_eq!(socket, None);
+
+    let (url, socket) = parse_upstream("unix:/run/llama.sock", None).unwrap();
+    assert_eq!(url, "http://localhost/");
+    assert_eq!(socket, Some(PathBuf::from("/run/llama.sock")));
+    let (url, _) = parse_upstream("unix:/run/llama.sock", Some("llama.internal:8080")).unwrap();
+    assert_eq!(url, "http://llama.internal:8080/");
+
+    assert!(parse_upstream("unix:/run/llama.sock", Some("bad host")).is_err());
+    assert!(parse_upstream("https://example.com/", Some("other.com")).is_err());
+}
+
+#[tokio::test]
+async fn unix_socket_with_proxy() {
+    let connector = Connector::new(Uri::from_static("http://localhost/"))
+        .unix_socket(Some("/nonexistent.sock".into()))
+        .proxy(Some(Uri::from_static("http://127.0.0.1:3128/")));
+    let err = connector.connect().await.err().unwrap();
+    assert!(err.to_string().contains("proxy"), "{err}");
+}
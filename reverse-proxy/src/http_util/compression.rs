@@ -0,0 +1,241 @@
+//! Response compression negotiated by `Accept-Encoding`.
+
+use std::io;
+
+use anyhow::Error;
+use async_compression::{
+    Level,
+    tokio::bufread::{BrotliEncoder, GzipEncoder},
+};
+use http::{HeaderMap, HeaderValue, Response, header};
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use hyper::body::{Body, Frame};
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::Outgoing;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Name used in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of precompressed files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gz",
+        }
+    }
+
+    /// Encodings accepted by the client in order of preference, brotli is preferred on ties.
+    pub fn accepted(headers: &HeaderMap) -> Vec<Self> {
+        let mut qualities = [None; 2];
+        let mut wildcard = None;
+        for value in headers.get_all(header::ACCEPT_ENCODING) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for item in value.split(',') {
+                let mut params = item.split(';');
+                let name = params.next().unwrap_or_default().trim();
+                let quality = params
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+                let Some(quality) = quality else {
+                    continue;
+                };
+                match name.to_ascii_lowercase().as_str() {
+                    "br" => qualities[0] = Some(quality),
+                    "gzip" | "x-gzip" => qualities[1] = Some(quality),
+                    "*" => wildcard = Some(quality),
+                    _ => (),
+                }
+            }
+        }
+        let mut accepted = [Self::Brotli, Self::Gzip]
+            .into_iter()
+            .zip(qualities)
+            .filter_map(|(encoding, quality)| Some((encoding, quality.or(wildcard)?)))
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect::<Vec<_>>();
+        accepted.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        accepted.into_iter().map(|(encoding, _)| encoding).collect()
+    }
+}
+
+/// Whether content of this MIME type is worth compressing.
+pub fn compressible(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence,
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/bmp"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+/// Brotli quality for compression on the fly, the default maximum quality is too slow for that.
+const BROTLI_QUALITY: i32 = 4;
+
+/// Compress body with the encoding on the fly.
+pub fn encode(body: Outgoing, encoding: Encoding) -> Outgoing {
+    let stream = BodyStream::new(body).filter_map(|frame| match frame {
+        Ok(frame) => frame.into_data().ok().map(Ok),
+        Err(err) => Some(Err(io::Error::other(err))),
+    });
+    let reader = StreamReader::new(stream);
+    let frames = |r: io::Result<_>| r.map(Frame::data).map_err(Error::from);
+    match encoding {
+        Encoding::Brotli => {
+            let encoder = BrotliEncoder::with_quality(reader, Level::Precise(BROTLI_QUALITY));
+            StreamBody::new(ReaderStream::new(encoder).map(frames)).boxed()
+        }
+        Encoding::Gzip => {
+            StreamBody::new(ReaderStream::new(GzipEncoder::new(reader)).map(frames)).boxed()
+        }
+    }
+}
+
+/// On-the-fly compression settings.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self { min_size: 1024 }
+    }
+}
+
+impl Compression {
+    /// Smaller content is not compressed as the gain doesn't pay off.
+    pub fn min_size(mut self, size: u64) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    /// Whether the content may be compressed, so that the response varies by `Accept-Encoding`.
+    pub fn applicable(&self, mime: &str, size: u64) -> bool {
+        size >= self.min_size && compressible(mime)
+    }
+
+    /// Encoding to compress the content with, if any.
+    pub fn negotiate(&self, accepted: &[Encoding], mime: &str, size: u64) -> Option<Encoding> {
+        accepted
+            .first()
+            .copied()
+            .filter(|_| self.applicable(mime, size))
+    }
+
+    /// Compress successful response of known size if the client accepts it.
+    pub fn compress(
+        &self,
+        accepted: &[Encoding],
+        mut res: Response<Outgoing>,
+    ) -> Response<Outgoing> {
+        let Some(size) = res.body().size_hint().exact() else {
+            return res;
+        };
+        let mime = match res.headers().get(header::CONTENT_TYPE) {
+            Some(mime) => mime.to_str().unwrap_or_default(),
+            None => return res,
+        };
+        if !res.status().is_success()
+            || res.headers().contains_key(header::CONTENT_ENCODING)
+            || !self.applicable(mime, size)
+        {
+            return res;
+        }
+        let encoding = self.negotiate(accepted, mime, size);
+        let headers = res.headers_mut();
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        let Some(encoding) = encoding else {
+            return res;
+        };
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.name()),
+        );
+        res.map(|body| encode(body, encoding))
+    }
+}
+
+#[test]
+fn accepted_encodings() {
+    let accepted = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, value.parse().unwrap());
+        Encoding::accepted(&headers)
+    };
+    assert_eq!(
+        accepted("gzip, deflate, br"),
+        [Encoding::Brotli, Encoding::Gzip]
+    );
+    assert_eq!(
+        accepted("br;q=0.5, gzip"),
+        [Encoding::Gzip, Encoding::Brotli]
+    );
+    assert_eq!(accepted("*;q=0.1, br;q=0"), [Encoding::Gzip]);
+    assert_eq!(accepted("identity"), []);
+    assert_eq!(Encoding::accepted(&HeaderMap::new()), []);
+}
+
+#[tokio::test]
+async fn compress_response() {
+    use async_compression::tokio::bufread::BrotliDecoder;
+    use http_body_util::Full;
+    use hyper::body::Bytes;
+    use tokio::io::AsyncReadExt;
+
+    let json = format!("{{\"content\": \"{}\"}}", "a".repeat(100));
+    let response = || {
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_LENGTH, json.len())
+            .body(
+                Full::new(Bytes::from(json.clone()))
+                    .map_err(Error::new)
+                    .boxed(),
+            )
+            .unwrap()
+    };
+    let compression = Compression::default().min_size(64);
+
+    let res = compression.compress(&[Encoding::Brotli, Encoding::Gzip], response());
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+    assert_eq!(res.headers()[header::VARY], "accept-encoding");
+    assert!(!res.headers().contains_key(header::CONTENT_LENGTH));
+    let data = res.into_body().collect().await.unwrap().to_bytes();
+    let mut decoded = String::new();
+    BrotliDecoder::new(data.as_ref())
+        .read_to_string(&mut decoded)
+        .await
+        .unwrap();
+    assert_eq!(decoded, json);
+
+    let res = compression.compress(&[], response());
+    assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(res.headers()[header::VARY], "accept-encoding");
+    let res = Compression::default().compress(&[Encoding::Gzip], response());
+    assert!(!res.headers().contains_key(header::VARY));
+}
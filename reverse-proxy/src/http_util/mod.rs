@@ -1,2 +1,5 @@
+pub mod client;
+pub mod compression;
+pub mod pool;
 pub mod proxy;
 pub mod sse;
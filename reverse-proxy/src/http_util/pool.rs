@@ -0,0 +1,401 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, OnceLock, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use http_body_util::Full;
+use hyper::{
+    Request, Response,
+    body::{Bytes, Incoming},
+};
+use tokio::task::JoinHandle;
+
+use super::client::{Connection, Connector};
+
+/// How often the pool evicts expired connections and establishes missing ones.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    min_idle: usize,
+    max_idle: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_idle: 0,
+            max_idle: 16,
+            idle_timeout: Some(Duration::from_secs(90)),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Number of idle connections kept open, they are established in the background once the pool is first used.
+    pub fn min_idle(mut self, min_idle: usize) -> Self {
+        self.min_idle = min_idle;
+        self
+    }
+
+    /// Maximum number of idle connections, excess connections are closed.
+    pub fn max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Idle connections that are not used for this time are closed.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+}
+
+struct Idle {
+    conn: Connection,
+    since: Instant,
+}
+
+struct PoolInner {
+    connector: Connector,
+    config: PoolConfig,
+    idle: Mutex<VecDeque<Idle>>,
+    /// Multiplexed connection used by all requests concurrently.
+    shared: Mutex<Option<Connection>>,
+    maintenance: OnceLock<JoinHandle<()>>,
+}
+
+/// Pool of upstream connections shared between all client connections.
+///
+/// Cloning the pool is cheap, clones share the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+impl Pool {
+    pub fn new(connector: Connector, config: PoolConfig) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                connector,
+                config,
+                idle: Mutex::new(VecDeque::new()),
+                shared: Mutex::new(None),
+                maintenance: OnceLock::new(),
+            }),
+        }
+    }
+
+    pub fn connector(&self) -> &Connector {
+        &self.inner.connector
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.inner.config
+    }
+
+    /// Number of idle connections, not counting the shared HTTP/2 one.
+    pub fn idle_connections(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    /// Send request using a shared HTTP/2 connection, an idle connection
+    /// or a new one if there are no available connections.
+    ///
+    /// If the pooled connection turns out to be closed by the server before the request was written,
+    /// the request is retried once using a new connection.
+    pub async fn send(&self, req: Request<Full<Bytes>>) -> Result<Response<Incoming>, Error> {
+        self.start_maintenance();
+
+        let (mut conn, reused) = match self.inner.take_shared().or_else(|| self.inner.take_idle()) {
+            Some(conn) => (conn, true),
+            None => (self.inner.connect().await?, false),
+        };
+        let res = match conn.try_send(req).await {
+            Ok(res) => res,
+            Err(mut err) => {
+                let req = err.take_message();
+                let err = err.into_error();
+                match req {
+                    Some(req) if reused && is_stale(&err) => {
+                        log::debug!("Pooled connection is stale ({err}), retrying with a new one");
+                        conn = self.inner.connect().await?;
+                        conn.send(req).await?
+                    }
+                    _ => return Err(err.into()),
+                }
+            }
+        };
+        self.release(conn);
+        Ok(res)
+    }
+
+    /// Return connection to the pool once it is ready to send the next request.
+    fn release(&self, mut conn: Connection) {
+        if conn.is_multiplexed() {
+            // Multiplexed connection is already in the pool.
+            return;
+        }
+        let pool = Arc::downgrade(&self.inner);
+        tokio::task::spawn(async move {
+            if conn.ready().await.is_err() {
+                return;
+            }
+            if let Some(inner) = pool.upgrade() {
+                inner.put(conn);
+            }
+        });
+    }
+
+    fn start_maintenance(&self) {
+        self.inner
+            .maintenance
+            .get_or_init(|| tokio::task::spawn(maintain(Arc::downgrade(&self.inner))));
+    }
+}
+
+impl PoolInner {
+    async fn connect(&self) -> Result<Connection, Error> {
+        let conn = self.connector.connect().await?;
+        if let Some(shared) = conn.try_clone() {
+            self.put(shared);
+        }
+        Ok(conn)
+    }
+
+    fn take_shared(&self) -> Option<Connection> {
+        let mut shared = self.shared.lock().unwrap();
+        match &*shared {
+            Some(conn) if !conn.is_closed() => conn.try_clone(),
+            Some(_) => {
+                *shared = None;
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn take_idle(&self) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(Idle { conn, since }) = idle.pop_back() {
+            if conn.is_closed() || self.is_expired(since) {
+                continue;
+            }
+            return Some(conn);
+        }
+        None
+    }
+
+    fn put(&self, conn: Connection) {
+        if conn.is_multiplexed() {
+            *self.shared.lock().unwrap() = Some(conn);
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() >= self.config.max_idle {
+            // Drop the oldest connection.
+            if idle.pop_front().is_none() {
+                return;
+            }
+        }
+        idle.push_back(Idle {
+            conn,
+            since: Instant::now(),
+        });
+    }
+
+    fn is_expired(&self, since: Instant) -> bool {
+        match self.config.idle_timeout {
+            Some(timeout) => since.elapsed() >= timeout,
+            None => false,
+        }
+    }
+
+    /// Remove closed and expired connections.
+    ///
+    /// Returns the number of connections that need to be established to reach `min_idle`.
+    fn evict(&self) -> usize {
+        if self.take_shared().is_some() {
+            // Multiplexed connection is enough for all requests.
+            return 0;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|Idle { conn, since }| !conn.is_closed() && !self.is_expired(*since));
+        self.config.min_idle.saturating_sub(idle.len())
+    }
+}
+
+impl Drop for PoolInner {
+    fn drop(&mut self) {
+        if let Some(task) = self.maintenance.get() {
+            task.abort();
+        }
+    }
+}
+
+async fn maintain(pool: Weak<PoolInner>) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let inner = match pool.upgrade() {
+            Some(inner) => inner,
+            None => break,
+        };
+        for _ in 0..inner.evict() {
+            match inner.connect().await {
+                // Multiplexed connection is already in the pool.
+                Ok(conn) if conn.is_multiplexed() => break,
+                Ok(conn) => inner.put(conn),
+                Err(err) => {
+                    log::warn!("Cannot establish idle connection: {err}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Error means that the connection was closed before the request could be sent.
+fn is_stale(err: &hyper::Error) -> bool {
+    err.is_canceled() || err.is_closed()
+}
+
+#[cfg(test)]
+struct TestServer {
+    url: hyper::Uri,
+    connections: Arc<std::sync::atomic::AtomicUsize>,
+    close: tokio::sync::watch::Sender<bool>,
+}
+
+/// Server answering `responses` requests per connection, then it reads the next request and closes the connection.
+///
+/// Connections are closed once `close` is set.
+#[cfg(test)]
+async fn test_server(responses: usize) -> TestServer {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let (close, closed) = tokio::sync::watch::channel(false);
+    tokio::task::spawn({
+        let connections = connections.clone();
+        async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                connections.fetch_add(1, Ordering::SeqCst);
+                let mut closed = closed.clone();
+                tokio::task::spawn(async move {
+                    let mut buf = [0; 1024];
+                    for _ in 0..responses {
+                        // Test requests are small enough to be read at once.
+                        tokio::select! {
+                            biased;
+                            _ = closed.wait_for(|closed| *closed) => return,
+                            n = stream.read(&mut buf) => if n.unwrap_or(0) == 0 {
+                                return;
+                            },
+                        }
+                        let res = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                        stream.write_all(res).await.unwrap();
+                    }
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        }
+    });
+    TestServer {
+        url: url.parse().unwrap(),
+        connections,
+        close,
+    }
+}
+
+#[cfg(test)]
+fn test_request(url: &hyper::Uri) -> Request<Full<Bytes>> {
+    Request::builder()
+        .uri("/")
+        .header(http::header::HOST, url.authority().unwrap().as_str())
+        .body(Full::new(Bytes::from_static(b"{}")))
+        .unwrap()
+}
+
+/// Wait until pooled idle connections satisfy the condition.
+#[cfg(test)]
+async fn wait_idle(pool: &Pool, f: impl Fn(&VecDeque<Idle>) -> bool) {
+    let ready = || f(&pool.inner.idle.lock().unwrap());
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !ready() {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("Pool didn't reach the expected state");
+}
+
+#[tokio::test]
+async fn reuse() {
+    use http_body_util::BodyExt;
+    use std::sync::atomic::Ordering;
+
+    let server = test_server(usize::MAX).await;
+    let pool = Pool::new(Connector::new(server.url.clone()), PoolConfig::default());
+    for _ in 0..3 {
+        let res = pool.send(test_request(&server.url)).await.unwrap();
+        assert_eq!(
+            &res.into_body().collect().await.unwrap().to_bytes()[..],
+            b"ok"
+        );
+        wait_idle(&pool, |idle| idle.len() == 1).await;
+    }
+    assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn stale() {
+    use http_body_util::BodyExt;
+    use std::sync::atomic::Ordering;
+
+    let server = test_server(usize::MAX).await;
+    let pool = Pool::new(Connector::new(server.url.clone()), PoolConfig::default());
+    let res = pool.send(test_request(&server.url)).await.unwrap();
+    res.into_body().collect().await.unwrap();
+    wait_idle(&pool, |idle| idle.len() == 1).await;
+
+    // Closed idle connection is replaced with a new one.
+    server.close.send(true).unwrap();
+    // Maintenance may evict the closed connection in the meantime.
+    wait_idle(&pool, |idle| idle.iter().all(|idle| idle.conn.is_closed())).await;
+    server.close.send(false).unwrap();
+    let res = pool.send(test_request(&server.url)).await.unwrap();
+    assert_eq!(
+        &res.into_body().collect().await.unwrap().to_bytes()[..],
+        b"ok"
+    );
+    assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn incomplete() {
+    use http_body_util::BodyExt;
+    use std::sync::atomic::Ordering;
+
+    let server = test_server(1).await;
+    let pool = Pool::new(Connector::new(server.url.clone()), PoolConfig::default());
+    let res = pool.send(test_request(&server.url)).await.unwrap();
+    res.into_body().collect().await.unwrap();
+    wait_idle(&pool, |idle| idle.len() == 1).await;
+
+    // Request written to the connection may have been processed, so it's not retried.
+    assert!(pool.send(test_request(&server.url)).await.is_err());
+    assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::HashSet,
+    env, fmt, fs, io,
+    net::SocketAddr,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::fs::{FileTypeExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    process,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use pin_project::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+/// Prefix of Unix domain socket addresses, e.g. `unix:/run/proxy.sock`.
+pub const UNIX_PREFIX: &str = "unix:";
+/// Prefix of sockets passed by systemd, e.g. `systemd:0` or `systemd:<name>` for `FileDescriptorName=`.
+pub const SYSTEMD_PREFIX: &str = "systemd:";
+
+/// First file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Indices of systemd sockets that are already taken, to not close the same descriptor twice.
+static SYSTEMD_TAKEN: Mutex<Option<HashSet<usize>>> = Mutex::new(None);
+
+/// Listener for incoming TCP or Unix domain socket connections.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix {
+        listener: UnixListener,
+        /// Socket file created by us, it is removed when the listener is dropped.
+        path: Option<PathBuf>,
+    },
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    /// Socket file is not removed when the listener is dropped.
+    fn from(listener: UnixListener) -> Self {
+        Self::Unix {
+            listener,
+            path: None,
+        }
+    }
+}
+
+impl Listener {
+    /// Bind to `host:port` or to `unix:<path>`, or take socket passed by systemd with `systemd:<index-or-name>`.
+    ///
+    /// `mode` sets permission bits of the socket file and is ignored for TCP and systemd sockets.
+    pub async fn bind(addr: &str, mode: Option<u32>) -> io::Result<Self> {
+        if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+            Self::bind_unix(path, mode).await
+        } else if let Some(name) = addr.strip_prefix(SYSTEMD_PREFIX) {
+            Self::from_systemd(name)
+        } else {
+            Ok(Self::Tcp(TcpListener::bind(addr).await?))
+        }
+    }
+
+    /// Take listening socket passed by systemd socket activation (`LISTEN_PID`/`LISTEN_FDS`).
+    ///
+    /// `name` is either an index of the socket or its name from `LISTEN_FDNAMES`.
+    pub fn from_systemd(name: &str) -> io::Result<Self> {
+        let not_found = |msg: String| io::Error::new(io::ErrorKind::NotFound, msg);
+        let var = |name: &str| env::var(name).ok();
+
+        if var("LISTEN_PID").and_then(|pid| pid.parse().ok()) != Some(process::id()) {
+            return Err(not_found(
+                "No sockets passed by systemd (LISTEN_PID is not set or doesn't match)".into(),
+            ));
+        }
+        let count: usize = var("LISTEN_FDS")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| not_found("LISTEN_FDS is not set or invalid".into()))?;
+        let index = match name.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => var("LISTEN_FDNAMES")
+                .and_then(|names| names.split(':').position(|n| n == name))
+                .ok_or_else(|| not_found(format!("No socket named {name:?} passed by systemd")))?,
+        };
+        if index >= count {
+            return Err(not_found(format!(
+                "Socket #{index} requested but systemd passed only {count}"
+            )));
+        }
+        if !SYSTEMD_TAKEN
+            .lock()
+            .unwrap()
+            .get_or_insert_default()
+            .insert(index)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Socket #{index} passed by systemd is already used"),
+            ));
+        }
+
+        // SAFETY: systemd passes ownership of `LISTEN_FDS` descriptors starting from 3 to our process,
+        // and each of them is taken only once.
+        let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START + index as RawFd) };
+        Self::from_fd(fd)
+    }
+
+    /// Create listener from inherited socket, detecting whether it is TCP or Unix domain socket.
+    fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        let unix = std::os::unix::net::UnixListener::from(fd);
+        // Getting address of non-Unix socket fails because of address family mismatch.
+        if unix.local_addr().is_ok() {
+            unix.set_nonblocking(true)?;
+            return Ok(UnixListener::from_std(unix)?.into());
+        }
+        let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+        tcp.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(tcp)?.into())
+    }
+
+    async fn bind_unix(path: impl AsRef<Path>, mode: Option<u32>) -> io::Result<Self> {
+        let path = path.as_ref();
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{path:?} exists and is not a socket"),
+                ));
+            }
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{path:?} is used by another process"),
+                ));
+            }
+            // Socket file left by previous process
+            log::debug!("Removing stale socket file {path:?}");
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        let listener = Self::Unix {
+            listener,
+            path: Some(path.to_owned()),
+        };
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(listener)
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), PeerAddr::Tcp(addr)))
+            }
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), PeerAddr::Unix))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Self::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            Self::Unix { listener, .. } => {
+                let addr = listener.local_addr()?;
+                match addr.as_pathname() {
+                    Some(path) => Ok(format!("{UNIX_PREFIX}{}", path.display())),
+                    None => Ok(format!("{UNIX_PREFIX}{addr:?}")),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix {
+            path: Some(path), ..
+        } = self
+            && let Err(err) = fs::remove_file(&*path)
+        {
+            log::warn!("Cannot remove socket file {path:?}: {err}");
+        }
+    }
+}
+
+/// Address of the connected client.
+///
+/// Server adds it to extensions of every incoming request.
+/// When PROXY protocol is enabled this is the client address reported by the proxy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Unix domain socket clients are usually unnamed.
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix => f.write_str("unix socket"),
+        }
+    }
+}
+
+/// Incoming TCP or Unix domain socket connection.
+#[pin_project(project = StreamProj)]
+pub enum Stream {
+    Tcp(#[pin] TcpStream),
+    Unix(#[pin] UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.project() {
+            StreamProj::Tcp(stream) => stream.poll_read(cx, buf),
+            StreamProj::Unix(stream) => stream.poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.project() {
+            StreamProj::Tcp(stream) => stream.poll_write(cx, buf),
+            StreamProj::Unix(stream) => stream.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            StreamProj::Tcp(stream) => stream.poll_flush(cx),
+            StreamProj::Unix(stream) => stream.poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            StreamProj::Tcp(stream) => stream.poll_shutdown(cx),
+            StreamProj::Unix(stream) => stream.poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.project() {
+            StreamProj::Tcp(stream) => stream.poll_write_vectored(cx, bufs),
+            StreamProj::Unix(stream) => stream.poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn unix_socket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = crate::test_util::TempDir::new("unix");
+    let path = dir.path().join("server.sock");
+    let addr = format!("{UNIX_PREFIX}{}", path.display());
+
+    // Stale socket file
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let listener = Listener::bind(&addr, Some(0o600)).await.unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addr);
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut client = UnixStream::connect(&path).await.unwrap();
+    let (mut stream, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer, PeerAddr::Unix);
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // Socket is in use
+    assert!(Listener::bind(&addr, None).await.is_err());
+
+    drop(listener);
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn inherited_fd() {
+    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = tcp.local_addr().unwrap();
+    let listener = Listener::from_fd(tcp.into()).unwrap();
+    assert!(matches!(listener, Listener::Tcp(_)));
+    assert_eq!(listener.local_addr().unwrap(), addr.to_string());
+    TcpStream::connect(addr).await.unwrap();
+    listener.accept().await.unwrap();
+
+    let dir = crate::test_util::TempDir::new("fd");
+    let path = dir.path().join("inherited.sock");
+    let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    let listener = Listener::from_fd(unix.into()).unwrap();
+    assert!(matches!(listener, Listener::Unix { path: None, .. }));
+    UnixStream::connect(&path).await.unwrap();
+    listener.accept().await.unwrap();
+    drop(listener);
+    // Socket file is owned by whoever created it
+    assert!(path.exists());
+}
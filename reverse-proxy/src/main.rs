@@ -1,24 +1,47 @@
-use std::{env, path::Path};
+use std::{env, path::Path, sync::Arc, time::Duration};
 
 use clap::Parser;
-use hyper::Uri;
+use globset::Glob;
+use hyper::{
+    Method, Uri,
+    header::{HeaderName, HeaderValue},
+};
+use regex::Regex;
 
 use llm_reverse_proxy::{
-    Router,
+    Nothing, Route, Router, Server, Shutdown,
+    admin::{Health, Metrics},
     files::FileServer,
-    openai::proxy::{ReverseProxy, ServerKind},
-    serve,
+    http_util::{client::parse_upstream, compression::Compression, pool::PoolConfig},
+    openai::{
+        self,
+        proxy::{ReverseProxy, ServerKind},
+    },
+    tls::{ClientTlsConfig, ServerTlsConfig},
 };
-use tokio::{fs::File, io::AsyncReadExt};
+use tokio::{fs::File, io::AsyncReadExt, task::JoinSet};
 
 #[derive(Clone, Debug, Parser)]
 struct Args {
-    /// Address to listen to client connections
+    /// Address to listen to client connections, `unix:<path>` for Unix domain socket,
+    /// `systemd:<index-or-name>` for socket passed by systemd socket activation, may be repeated
     #[arg(short, long, default_value = "0.0.0.0:4000")]
-    addr: String,
-    /// Server URL where client connection should be forwarded
+    addr: Vec<String>,
+    /// Address to serve `/health` and `/metrics` endpoints, same formats as `--addr`, may be repeated
+    #[arg(long)]
+    admin_addr: Vec<String>,
+    /// Expect PROXY protocol header from the load balancer on client connections
+    #[arg(long)]
+    proxy_protocol: bool,
+    /// Permission bits of Unix domain socket file in octal, e.g. `660`
+    #[arg(long, value_parser = |s: &str| u32::from_str_radix(s, 8))]
+    unix_mode: Option<u32>,
+    /// Server URL where client connection should be forwarded, `unix:<path>` for Unix domain socket
     #[arg(short, long)]
     server: String,
+    /// Host sent to the upstream server listening on Unix domain socket, `localhost` by default
+    #[arg(long)]
+    server_host: Option<String>,
     /// HTTP proxy address
     #[arg(long)]
     proxy: Option<String>,
@@ -28,6 +51,100 @@ struct Args {
     /// Static file server root path
     #[arg(long)]
     files: Option<String>,
+    /// Header added to static file responses in format `<name>: <value>`, may be repeated,
+    /// empty value removes the default header (`Content-Security-Policy`, `X-Content-Type-Options`, `Referrer-Policy`)
+    #[arg(long, requires = "files")]
+    files_header: Vec<String>,
+    /// `Cache-Control` for static files matching glob in format `<glob>=<value>`, may be repeated,
+    /// e.g. `build/**=public, max-age=86400`
+    #[arg(long, requires = "files")]
+    files_cache_control: Vec<String>,
+    /// Compute `ETag` from content hash for static files matching glob, e.g. `build/*.js`, may be repeated
+    #[arg(long, requires = "files")]
+    files_content_etag: Vec<String>,
+    /// Serve `index.html` for unknown static paths without extension, for client-side routing
+    #[arg(long, requires = "files")]
+    files_spa: bool,
+    /// Page served for missing static files, relative to the files root, e.g. `404.html`
+    #[arg(long, requires = "files")]
+    files_not_found_page: Option<String>,
+    /// Page served when a static file cannot be read, relative to the files root
+    #[arg(long, requires = "files")]
+    files_error_page: Option<String>,
+    /// Don't compress static files and proxy responses on the fly (precompressed files are still served)
+    #[arg(long)]
+    no_compression: bool,
+    /// Minimum size of content in bytes to be compressed on the fly
+    #[arg(long, default_value_t = 1024)]
+    compression_min_size: u64,
+    /// Path prefix to serve everything under, e.g. `/llm`
+    #[arg(long)]
+    base_path: Option<String>,
+    /// Rewrite request path before routing, in format `<regex>=<replacement>`, may be repeated
+    #[arg(long)]
+    rewrite: Vec<String>,
+    /// Certificate chain PEM file to serve HTTPS to clients
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// Private key PEM file to serve HTTPS to clients
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// Additional certificate selected by SNI in format `<hostname>=<cert-file>,<key-file>`, may be repeated
+    #[arg(long, requires = "tls_cert")]
+    tls_sni: Vec<String>,
+    /// PEM file with additional CA certificates trusted for the upstream server
+    #[arg(long)]
+    server_tls_ca_file: Option<String>,
+    /// Directory with additional CA certificates (in OpenSSL hashed format) trusted for the upstream server
+    #[arg(long)]
+    server_tls_ca_dir: Option<String>,
+    /// Client certificate chain PEM file for mutual TLS with the upstream server
+    #[arg(long, requires = "server_tls_client_key")]
+    server_tls_client_cert: Option<String>,
+    /// Client private key PEM file for mutual TLS with the upstream server
+    #[arg(long, requires = "server_tls_client_cert")]
+    server_tls_client_key: Option<String>,
+    /// Disable upstream TLS certificate verification (for testing only!)
+    #[arg(long)]
+    server_tls_insecure: bool,
+    /// Maximum number of concurrent client connections per listener, 0 means unlimited
+    #[arg(long, default_value_t = 1024)]
+    max_connections: usize,
+    /// Time in seconds given to clients to send request headers, 0 disables the timeout
+    #[arg(long, default_value_t = 30)]
+    header_read_timeout: u64,
+    /// Time in seconds after which client connections without requests are closed, 0 disables the timeout
+    #[arg(long, default_value_t = 60)]
+    keep_alive_timeout: u64,
+    /// Time in seconds given to in-flight requests to finish on shutdown
+    #[arg(long, default_value_t = 10)]
+    shutdown_grace_period: u64,
+    /// Maximum size of client request body in bytes
+    #[arg(long, default_value_t = 16 << 20)]
+    max_request_size: usize,
+    /// Time in seconds to wait for the upstream response headers, 0 disables the timeout
+    #[arg(long, default_value_t = 0)]
+    upstream_timeout: u64,
+    /// Pass upstream error bodies as is instead of converting them to OpenAI format
+    #[arg(long)]
+    raw_upstream_errors: bool,
+    /// Don't negotiate HTTP/2 with the upstream server
+    #[arg(long)]
+    http1_only: bool,
+    /// Number of idle upstream connections kept open in advance
+    #[arg(long, default_value_t = 0)]
+    pool_min_idle: usize,
+    /// Maximum number of idle upstream connections
+    #[arg(long, default_value_t = 16)]
+    pool_max_idle: usize,
+    /// Idle upstream connection timeout in seconds, 0 disables the timeout
+    #[arg(long, default_value_t = 90)]
+    pool_idle_timeout: u64,
+}
+
+/// Zero means that the timeout is disabled.
+fn secs_or_none(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs)).filter(|d| !d.is_zero())
 }
 
 #[tokio::main]
@@ -35,7 +152,8 @@ async fn main() {
     env_logger::builder().init();
     let args = Args::parse();
 
-    let server_url = args.server.parse::<Uri>().expect("Cannot parse server URL");
+    let (server_url, server_socket) =
+        parse_upstream(&args.server, args.server_host.as_deref()).expect("Cannot parse server URL");
     assert!(matches!(server_url.scheme_str(), Some("http" | "https")));
     assert!(server_url.authority().is_some());
     assert!(server_url.path() == "/");
@@ -56,13 +174,47 @@ async fn main() {
         .expect("Cannot parse HTTP proxy URL")
         .inspect(|url| log::info!("Using HTTP proxy: {url}"));
 
+    let compression = Some(Compression::default().min_size(args.compression_min_size))
+        .filter(|_| !args.no_compression);
+
     let file_server = if let Some(path) = &args.files {
         let path = Path::new(path);
         assert!(
             path.is_dir(),
             "Static path doesn't exist or is not a directory"
         );
-        Some(FileServer::new(path))
+        let mut file_server = FileServer::new(path)
+            .compression(compression.clone())
+            .spa_fallback(args.files_spa)
+            .not_found_page(args.files_not_found_page.clone())
+            .error_page(args.files_error_page.clone());
+        for header in &args.files_header {
+            let (name, value) = header
+                .split_once(':')
+                .expect("File header must be in format `<name>: <value>`");
+            let name = HeaderName::try_from(name.trim()).expect("Invalid file header name");
+            file_server = match value.trim() {
+                "" => file_server.remove_header(name),
+                value => file_server.header(
+                    name,
+                    HeaderValue::try_from(value).expect("Invalid file header value"),
+                ),
+            };
+        }
+        for rule in &args.files_cache_control {
+            let (glob, value) = rule
+                .split_once('=')
+                .expect("Cache control rule must be in format `<glob>=<value>`");
+            file_server = file_server.cache_control(
+                Glob::new(glob).expect("Invalid cache control glob"),
+                HeaderValue::try_from(value).expect("Invalid cache control value"),
+            );
+        }
+        for glob in &args.files_content_etag {
+            file_server =
+                file_server.content_etag(Glob::new(glob).expect("Invalid content ETag glob"));
+        }
+        Some(file_server)
     } else {
         None
     };
@@ -70,7 +222,7 @@ async fn main() {
     if let Err(e) = dotenvy::dotenv() {
         log::warn!("Cannot load .env file: {e}");
     }
-    let api_key = if let ServerKind::OpenAi { .. } = &server_kind {
+    let api_key = if let ServerKind::OpenAi = &server_kind {
         assert!(server_url.scheme_str() == Some("https"));
         Some(env::var("OPENAI_API_KEY").expect("OpenAI API key is not set"))
     } else {
@@ -96,17 +248,113 @@ async fn main() {
     };
     log::info!("System prompt: {system_prompt:?}");
 
-    let res = serve(args.addr, async move || {
-        Ok(Router::new(file_server.clone()).push(
-            "/chat/completions",
-            ReverseProxy::new(server_url.clone())
-                .proxy(proxy_url.clone())
-                .kind(server_kind)
-                .model(model_name.clone())
-                .api_key(api_key.clone())
-                .system_prompt(system_prompt.clone()),
-        ))
-    })
+    let tls_acceptor = args.tls_cert.zip(args.tls_key).map(|(cert, key)| {
+        let mut config = ServerTlsConfig::new(cert, key);
+        for sni in &args.tls_sni {
+            let (hostname, cert, key) = sni
+                .split_once('=')
+                .and_then(|(hostname, files)| {
+                    let (cert, key) = files.split_once(',')?;
+                    Some((hostname, cert, key))
+                })
+                .expect("SNI certificate must be in format `<hostname>=<cert-file>,<key-file>`");
+            config = config.sni(hostname, cert, key);
+        }
+        config.build().expect("Cannot load TLS certificates")
+    });
+
+    let server_tls_config = ClientTlsConfig::default()
+        .ca_file(args.server_tls_ca_file)
+        .ca_dir(args.server_tls_ca_dir)
+        .client_cert(args.server_tls_client_cert.zip(args.server_tls_client_key))
+        .insecure(args.server_tls_insecure);
+
+    let pool_config = PoolConfig::default()
+        .min_idle(args.pool_min_idle)
+        .max_idle(args.pool_max_idle)
+        .idle_timeout(secs_or_none(args.pool_idle_timeout));
+
+    // Proxy is shared between all client connections.
+    let reverse_proxy = ReverseProxy::new(server_url)
+        .unix_socket(server_socket)
+        .proxy(proxy_url)
+        .tls(server_tls_config)
+        .http2(!args.http1_only)
+        .pool(pool_config)
+        .max_request_size(args.max_request_size)
+        .response_timeout(secs_or_none(args.upstream_timeout))
+        .normalize_errors(!args.raw_upstream_errors)
+        .compression(compression)
+        .kind(server_kind)
+        .model(model_name)
+        .api_key(api_key)
+        .system_prompt(system_prompt);
+
+    let shutdown = Shutdown::new();
+    shutdown
+        .stop_on_signal()
+        .expect("Cannot set up signal handlers");
+
+    let metrics = Metrics::new();
+    metrics.upstream(&args.server, reverse_proxy.connection_pool().clone());
+
+    let server = Server::new()
+        .shutdown(shutdown)
+        .metrics(metrics.clone())
+        .grace_period(Duration::from_secs(args.shutdown_grace_period))
+        .unix_mode(args.unix_mode)
+        .header_read_timeout(secs_or_none(args.header_read_timeout))
+        .idle_timeout(secs_or_none(args.keep_alive_timeout));
+
+    let res = async {
+        // Bind all listeners before serving, so that the process fails early if any address is busy.
+        let public_server = server
+            .clone()
+            .tls(tls_acceptor)
+            .max_connections(Some(args.max_connections).filter(|n| *n != 0))
+            .proxy_protocol(args.proxy_protocol);
+        let mut public = vec![];
+        for addr in &args.addr {
+            public.push(public_server.clone().bind(addr).await?);
+        }
+        let mut admin = vec![];
+        for addr in &args.admin_addr {
+            admin.push(server.clone().bind(addr).await?);
+        }
+
+        // Routers are shared between all client connections.
+        let mut public_router = openai::routes(Router::new(file_server), reverse_proxy);
+        if let Some(base_path) = &args.base_path {
+            public_router = Router::new(Nothing).mount(base_path, public_router);
+        }
+        for rule in &args.rewrite {
+            let (regex, replacement) = rule
+                .split_once('=')
+                .expect("Rewrite rule must be in format `<regex>=<replacement>`");
+            let regex = Regex::new(regex).expect("Invalid rewrite regex");
+            public_router = public_router.rewrite(regex, replacement);
+        }
+        let public_router = Arc::new(public_router);
+        let admin_router = Arc::new(
+            Router::new(Nothing)
+                .route(Route::new("/health").method(Method::GET), Health)
+                .route(Route::new("/metrics").method(Method::GET), metrics),
+        );
+
+        let mut listeners = JoinSet::new();
+        for (bound, router) in public
+            .into_iter()
+            .map(|bound| (bound, public_router.clone()))
+            .chain(admin.into_iter().map(|bound| (bound, admin_router.clone())))
+        {
+            listeners.spawn(bound.serve(async move || Ok(router.clone())));
+        }
+        // Any listener failing stops the whole process.
+        while let Some(res) = listeners.join_next().await {
+            res??;
+        }
+        Ok::<_, anyhow::Error>(())
+    }
     .await;
     if let Err(e) = res {
         log::error!("Error running server: {e}");
@@ -1,11 +1,28 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use smallvec::SmallVec;
 use std::borrow::Cow;
 
+/// Fields that are not interpreted by the proxy but passed through as is.
+pub type Extra = Map<String, Value>;
+
+/// Message content, either text or an array of parts like text and images.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content<'a> {
+    Text(Cow<'a, str>),
+    Parts(Vec<Value>),
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message<'a> {
     pub role: Cow<'a, str>,
-    pub content: Cow<'a, str>,
+    /// `null` or missing for assistant messages with tool calls.
+    #[serde(default)]
+    pub content: Option<Content<'a>>,
+    /// `name`, `tool_calls`, `tool_call_id`, etc.
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -13,6 +30,9 @@ pub struct Request<'a> {
     pub model: Cow<'a, str>,
     pub messages: Vec<Message<'a>>,
     pub stream: Option<bool>,
+    /// Sampling parameters, `stream_options`, etc.
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -20,17 +40,26 @@ pub struct Choice<'a> {
     pub message: Message<'a>,
     pub index: Option<usize>,
     pub finish_reason: Option<String>,
+    /// `logprobs`, etc.
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Response<'a> {
     pub choices: SmallVec<[Choice<'a>; 1]>,
+    /// `id`, `model`, `usage`, etc.
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Delta<'a> {
     pub content: Option<Cow<'a, str>>,
     pub role: Option<Cow<'a, str>>,
+    /// `tool_calls`, `refusal`, etc.
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -38,9 +67,105 @@ pub struct StreamChoice<'a> {
     pub delta: Delta<'a>,
     pub index: Option<usize>,
     pub finish_reason: Option<Cow<'a, str>>,
+    /// `logprobs`, etc.
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ResponseStreamChunk<'a> {
     pub choices: SmallVec<[StreamChoice<'a>; 1]>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiError<'a> {
+    pub message: Cow<'a, str>,
+    #[serde(rename = "type")]
+    pub type_: Cow<'a, str>,
+    pub code: Option<Cow<'a, str>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorResponse<'a> {
+    pub error: ApiError<'a>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Model<'a> {
+    pub id: Cow<'a, str>,
+    pub object: Cow<'a, str>,
+    pub created: u64,
+    pub owned_by: Cow<'a, str>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelList<'a> {
+    pub object: Cow<'a, str>,
+    pub data: Vec<Model<'a>>,
+}
+
+#[test]
+fn sdk_payloads() {
+    use serde_json::json;
+
+    let req = json!({
+        "model": "gpt-4o",
+        "messages": [
+            {"role": "user", "content": [
+                {"type": "text", "text": "What is on the image?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+            ]},
+            {"role": "assistant", "content": null, "tool_calls": [
+                {"id": "call_1", "type": "function", "function": {"name": "describe", "arguments": "{}"}},
+            ]},
+            {"role": "tool", "tool_call_id": "call_1", "content": "a cat"},
+        ],
+        "tools": [{"type": "function", "function": {"name": "describe", "parameters": {}}}],
+        "stream": true,
+    });
+    let parsed: Request = serde_json::from_value(req.clone()).unwrap();
+    assert!(
+        matches!(parsed.messages[0].content, Some(Content::Parts(ref parts)) if parts.len() == 2)
+    );
+    assert_eq!(parsed.messages[1].content, None);
+    assert_eq!(
+        parsed.messages[2].content,
+        Some(Content::Text("a cat".into()))
+    );
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), req);
+
+    let chunk = json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion.chunk",
+        "choices": [{
+            "index": 0,
+            "delta": {"role": "assistant", "content": null, "tool_calls": [
+                {"index": 0, "id": "call_1", "type": "function", "function": {"name": "describe", "arguments": ""}},
+            ]},
+            "logprobs": null,
+            "finish_reason": null,
+        }],
+    });
+    let parsed: ResponseStreamChunk = serde_json::from_value(chunk.clone()).unwrap();
+    assert_eq!(
+        parsed.choices[0].delta.extra["tool_calls"][0]["id"],
+        "call_1"
+    );
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), chunk);
+
+    let res = json!({
+        "id": "chatcmpl-2",
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": null, "tool_calls": [
+                {"id": "call_2", "type": "function", "function": {"name": "describe", "arguments": "{}"}},
+            ]},
+            "logprobs": null,
+            "finish_reason": "tool_calls",
+        }],
+    });
+    let parsed: Response = serde_json::from_value(res.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), res);
 }
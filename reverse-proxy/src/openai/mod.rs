@@ -1,2 +1,131 @@
 pub mod api;
+pub mod models;
 pub mod proxy;
+
+use http::Method;
+
+use crate::{Route, Router};
+
+use self::{models::Models, proxy::ReverseProxy};
+
+/// Add OpenAI-compatible endpoints served by `proxy`.
+///
+/// Endpoints are available both under `/v1` and at bare paths,
+/// so clients work whether their base URL ends with `/v1` or not.
+pub fn routes(mut router: Router, proxy: ReverseProxy) -> Router {
+    let models = Models::new([proxy.model_id()]);
+    for base in ["", "/v1"] {
+        router = router
+            .route(
+                Route::new(&format!("{base}/chat/completions")).method(Method::POST),
+                proxy.clone(),
+            )
+            .route(
+                Route::new(&format!("{base}/models")).method(Method::GET),
+                models.clone(),
+            )
+            .route(
+                Route::new(&format!("{base}/models/{{id}}")).method(Method::GET),
+                models.clone(),
+            );
+    }
+    router
+}
+
+#[tokio::test]
+async fn openai_surface() {
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Error;
+    use http::{Request, Response};
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::{Bytes, Incoming};
+    use serde_json::{Value, json};
+
+    use crate::{Nothing, Outgoing, Server, Service, server::test_request};
+
+    /// Upstream that remembers the last request body.
+    #[derive(Clone, Default)]
+    struct Upstream(Arc<Mutex<Value>>);
+    impl Service for Upstream {
+        async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+            assert_eq!(req.uri().path(), "/chat/completions");
+            let body = req.into_body().collect().await?.to_bytes();
+            *self.0.lock().unwrap() = serde_json::from_slice(&body)?;
+            let res = json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}],
+                "usage": {"total_tokens": 3},
+            });
+            Ok(Response::new(
+                Full::from(res.to_string()).map_err(Error::new).boxed(),
+            ))
+        }
+    }
+
+    let upstream = Upstream::default();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let server = Server::new().bind_listener(listener).unwrap();
+    tokio::spawn(server.serve({
+        let upstream = upstream.clone();
+        async move || Ok(upstream.clone())
+    }));
+
+    let proxy = ReverseProxy::new(url.parse().unwrap()).max_request_size(1024);
+    let unreachable = ReverseProxy::new("http://127.0.0.1:1/".parse().unwrap());
+    let router = Arc::new(
+        routes(Router::new(Nothing), proxy)
+            .route(Route::new("/unreachable").method(Method::POST), unreachable),
+    );
+    let request = async |method: Method, path: &str, body: Value| {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap();
+        let res = test_request(router.clone(), req).await;
+        (
+            res.status().as_u16(),
+            serde_json::from_slice::<Value>(res.body()).unwrap_or_default(),
+        )
+    };
+
+    for path in ["/chat/completions", "/v1/chat/completions"] {
+        let body = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "hello"}],
+            "temperature": 0.5,
+        });
+        let (status, res) = request(Method::POST, path, body).await;
+        assert_eq!(status, 200);
+        assert_eq!(res["id"], "chatcmpl-1");
+        assert_eq!(res["usage"]["total_tokens"], 3);
+        assert_eq!(res["choices"][0]["message"]["content"], "hi");
+        assert_eq!(upstream.0.lock().unwrap()["temperature"], 0.5);
+    }
+
+    let (status, res) = request(Method::GET, "/v1/models", Value::Null).await;
+    assert_eq!(status, 200);
+    assert_eq!(res["data"][0]["id"], "default");
+    let (status, _) = request(Method::GET, "/models/default", Value::Null).await;
+    assert_eq!(status, 200);
+    let (status, res) = request(Method::GET, "/v1/models/gpt-5", Value::Null).await;
+    assert_eq!(status, 404);
+    assert_eq!(res["error"]["code"], "model_not_found");
+
+    let (status, res) = request(Method::POST, "/v1/chat/completions", json!("hello")).await;
+    assert_eq!(status, 400);
+    assert_eq!(res["error"]["type"], "invalid_request_error");
+    let body = json!({"messages": [{"role": "user", "content": "a".repeat(1024)}]});
+    let (status, _) = request(Method::POST, "/v1/chat/completions", body).await;
+    assert_eq!(status, 413);
+    let (status, res) = request(Method::GET, "/v1/chat/completions", Value::Null).await;
+    assert_eq!(status, 405);
+    assert_eq!(res["error"]["type"], "invalid_request_error");
+    let body = json!({"model": "m", "messages": []});
+    let (status, res) = request(Method::POST, "/unreachable", body).await;
+    assert_eq!(status, 502);
+    assert_eq!(res["error"]["type"], "server_error");
+}
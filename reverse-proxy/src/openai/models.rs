@@ -0,0 +1,54 @@
+use anyhow::Error;
+use http::{Request, Response, header};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+
+use crate::{Outgoing, Service, error::ProxyError, openai::api, router::PathParams};
+
+/// `/models` and `/models/{id}` endpoints listing models available through the proxy.
+#[derive(Clone, Debug)]
+pub struct Models {
+    ids: Vec<String>,
+}
+
+impl Models {
+    pub fn new(ids: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            ids: ids.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn model(id: &str) -> api::Model<'_> {
+        api::Model {
+            id: id.into(),
+            object: "model".into(),
+            created: 0,
+            owned_by: "llm-reverse-proxy".into(),
+        }
+    }
+}
+
+impl Service for Models {
+    async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+        let id = req
+            .extensions()
+            .get::<PathParams>()
+            .and_then(|p| p.get("id"));
+        let data = match id {
+            None => serde_json::to_vec(&api::ModelList {
+                object: "list".into(),
+                data: self.ids.iter().map(|id| Self::model(id)).collect(),
+            })?,
+            Some(id) if self.ids.iter().any(|m| m == id) => serde_json::to_vec(&Self::model(id))?,
+            Some(id) => {
+                let message = format!("The model '{id}' does not exist");
+                return Ok(ProxyError::not_found(message)
+                    .code("model_not_found")
+                    .into_response());
+            }
+        };
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(data)).map_err(Error::new).boxed())?)
+    }
+}
@@ -1,119 +1,42 @@
-use std::{convert::Infallible, pin::Pin};
+use std::{
+    convert::Infallible,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use anyhow::{Error, anyhow, bail};
-use http::{header, uri::PathAndQuery};
-use http_body_util::{BodyExt, BodyStream, Full, StreamBody};
+use anyhow::{Error, bail};
+use http::{HeaderValue, StatusCode, header, uri::PathAndQuery};
+use http_body_util::{BodyExt, BodyStream, Full, LengthLimitError, Limited, StreamBody};
 use hyper::{
     Request, Response, Uri,
     body::{Bytes, Frame, Incoming},
-    client::conn::http1::SendRequest,
-};
-use hyper_util::rt::TokioIo;
-use openssl::ssl::{Ssl, SslContext, SslMethod};
-use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::TcpStream,
-    sync::Mutex,
-    task::JoinHandle,
 };
-use tokio_openssl::SslStream;
-use tokio_stream::StreamExt;
+use pin_project::pin_project;
+use serde_json::Value;
+use tokio::time::timeout;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::{
     Outgoing, Service,
+    error::{ErrorKind, ProxyError},
     http_util::{
-        self,
+        client::Connector,
+        compression::{Compression, Encoding},
+        pool::{Pool, PoolConfig},
         sse::{Event, EventReader},
     },
-    openai::api::{self, Message},
+    openai::api::{self, Content, Message},
+    request_id::{RequestId, X_REQUEST_ID, X_UPSTREAM_REQUEST_ID},
+    shutdown::Shutdown,
+    tls::ClientTlsConfig,
 };
 
-fn url_to_host_and_port(url: &Uri) -> Result<(&str, u16), Error> {
-    // Get the host and the port
-    let host = url
-        .host()
-        .ok_or_else(|| anyhow!("URL has no host: {url}"))?;
-    let port = url.port_u16();
-    match url
-        .scheme_str()
-        .ok_or_else(|| anyhow!("Server address has no scheme: {url}"))?
-    {
-        "http" => Ok((host, port.unwrap_or(80))),
-        "https" => Ok((host, port.unwrap_or(443))),
-        scheme => Err(anyhow!("Unsupported scheme: {scheme}")),
-    }
-}
-
-struct Connection {
-    sender: SendRequest<Full<Bytes>>,
-    task: JoinHandle<()>,
-}
-
-impl Connection {
-    async fn connect(url: &Uri) -> Result<Self, Error> {
-        // Open a TCP connection to the remote host
-        let stream = TcpStream::connect(url_to_host_and_port(url)?).await?;
-        Self::connect_raw_socket(stream, url).await
-    }
-
-    async fn connect_through_proxy(proxy: &Uri, dst: &Uri) -> Result<Self, Error> {
-        let mut stream = TcpStream::connect(url_to_host_and_port(proxy)?).await?;
-        http_util::proxy::handshake(&mut stream, url_to_host_and_port(dst)?).await?;
-        Self::connect_raw_socket(stream, dst).await
-    }
-
-    async fn connect_raw_socket(stream: TcpStream, url: &Uri) -> Result<Self, Error> {
-        let addr = url_to_host_and_port(url)?;
-        match url.scheme_str().expect("Server address has no scheme") {
-            "http" => Self::connect_stream(stream, addr).await,
-            "https" => {
-                let ssl_context = SslContext::builder(SslMethod::tls())?.build();
-                let mut ssl = Ssl::new(&ssl_context)?;
-                ssl.set_hostname(addr.0)?;
-                let mut ssl_stream = SslStream::new(ssl, stream)?;
-                Pin::new(&mut ssl_stream).connect().await?;
-
-                Self::connect_stream(ssl_stream, addr).await
-            }
-            scheme => bail!("Unsupported scheme: {scheme}"),
-        }
-    }
-
-    async fn connect_stream<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
-        stream: S,
-        addr: (&str, u16),
-    ) -> Result<Self, Error> {
-        let addr = format!("{}:{}", addr.0, addr.1);
-
-        // Use an adapter to access something implementing `tokio::io` traits as if they implement
-        // `hyper::rt` IO traits.
-        let io = TokioIo::new(stream);
-
-        // Create the Hyper client
-        let (sender, conn) = hyper::client::conn::http1::handshake(io).await?;
-        log::debug!("Outgoing connection to {addr} established");
-
-        // Spawn a task to poll the connection, driving the HTTP state
-        let task = tokio::task::spawn(async move {
-            if let Err(err) = conn.await {
-                log::error!("Outgoing connection to {addr} failed: {:?}", err);
-            } else {
-                log::debug!("Outgoing connection to {addr} closed");
-            }
-        });
-
-        Ok(Self { sender, task })
-    }
-
-    async fn send(&mut self, req: Request<Full<Bytes>>) -> Result<Response<Incoming>, Error> {
-        Ok(self.sender.send_request(req).await?)
-    }
-
-    fn is_closed(&self) -> bool {
-        self.task.is_finished()
-    }
-}
-
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
 pub enum ServerKind {
     #[default]
@@ -121,9 +44,12 @@ pub enum ServerKind {
     OpenAi,
 }
 
+/// OpenAI API reverse proxy.
+///
+/// Cloning is cheap, clones share the same upstream connection pool.
+#[derive(Clone)]
 pub struct ReverseProxy {
     url: Uri,
-    proxy: Option<Uri>,
 
     model: String,
     kind: ServerKind,
@@ -131,19 +57,27 @@ pub struct ReverseProxy {
     api_key: Option<String>,
     system_prompt: Option<String>,
 
-    connection: Mutex<Option<Connection>>,
+    max_request_size: usize,
+    response_timeout: Option<Duration>,
+    normalize_errors: bool,
+    compression: Option<Compression>,
+
+    pool: Pool,
 }
 
 impl ReverseProxy {
     pub fn new(url: Uri) -> Self {
         Self {
+            pool: Pool::new(Connector::new(url.clone()), PoolConfig::default()),
             url,
-            proxy: None,
             model: String::new(),
             kind: ServerKind::default(),
             api_key: None,
             system_prompt: None,
-            connection: Mutex::new(None),
+            max_request_size: 16 << 20,
+            response_timeout: None,
+            normalize_errors: true,
+            compression: Some(Compression::default()),
         }
     }
 
@@ -157,11 +91,52 @@ impl ReverseProxy {
         self
     }
 
-    pub fn proxy(mut self, proxy: Option<Uri>) -> Self {
-        self.proxy = proxy;
+    pub fn proxy(self, proxy: Option<Uri>) -> Self {
+        let connector = self.pool.connector().clone().proxy(proxy);
+        self.connector(connector)
+    }
+
+    /// Connect to the upstream server via Unix domain socket.
+    pub fn unix_socket(self, path: Option<PathBuf>) -> Self {
+        let connector = self.pool.connector().clone().unix_socket(path);
+        self.connector(connector)
+    }
+
+    /// Negotiate HTTP/2 with the upstream server when possible.
+    pub fn http2(self, enabled: bool) -> Self {
+        let connector = self.pool.connector().clone().http2(enabled);
+        self.connector(connector)
+    }
+
+    pub fn tls(self, config: ClientTlsConfig) -> Self {
+        let connector = self.pool.connector().clone().tls(config);
+        self.connector(connector)
+    }
+
+    pub fn pool(mut self, config: PoolConfig) -> Self {
+        self.pool = Pool::new(self.pool.connector().clone(), config);
+        self
+    }
+
+    fn connector(mut self, connector: Connector) -> Self {
+        self.pool = Pool::new(connector, self.pool.config().clone());
         self
     }
 
+    /// Model name reported to clients.
+    pub fn model_id(&self) -> &str {
+        match self.model.as_str() {
+            // llama.cpp serves a single model regardless of the name
+            "" => "default",
+            model => model,
+        }
+    }
+
+    /// Upstream connection pool shared by clones of the proxy.
+    pub fn connection_pool(&self) -> &Pool {
+        &self.pool
+    }
+
     pub fn api_key(mut self, api_key: Option<String>) -> Self {
         self.api_key = api_key;
         self
@@ -172,61 +147,90 @@ impl ReverseProxy {
         self
     }
 
-    async fn send(&self, req: Request<Full<Bytes>>) -> Result<Response<Incoming>, Error> {
-        let mut guard = self.connection.lock().await;
-        let conn = loop {
-            let conn = match guard.take() {
-                Some(conn) => conn,
-                None => match &self.proxy {
-                    None => Connection::connect(&self.url).await?,
-                    Some(proxy) => Connection::connect_through_proxy(proxy, &self.url).await?,
-                },
-            };
-            if conn.is_closed() {
-                continue;
-            }
-            break guard.insert(conn);
-        };
-        conn.send(req).await
+    /// Maximum size of client request body in bytes, larger requests are rejected with `413`.
+    pub fn max_request_size(mut self, size: usize) -> Self {
+        self.max_request_size = size;
+        self
+    }
+
+    /// Time to wait for upstream response headers before responding with `504`.
+    pub fn response_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.response_timeout = timeout;
+        self
+    }
+
+    /// Convert upstream error bodies that are not in OpenAI format (e.g. from llama.cpp) to it.
+    ///
+    /// Enabled by default. Upstream status code and rate limit headers are kept anyway.
+    pub fn normalize_errors(mut self, enabled: bool) -> Self {
+        self.normalize_errors = enabled;
+        self
+    }
+
+    /// Compress non-streaming responses when the client accepts it, enabled by default.
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
     }
 }
 
 impl Service for ReverseProxy {
     async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
-        match self.forward(req).await {
+        let id = RequestId::get(&req)
+            .cloned()
+            .unwrap_or_else(RequestId::generate);
+        match self.forward(req, &id).await {
             Ok(res) => Ok(res),
             Err(err) => {
-                log::error!("Reverse-proxy forwarding error:\n{err}");
-                Ok(Response::builder()
-                    .status(500)
-                    .header(header::CONTENT_TYPE, "text/plain")
-                    .body(
-                        Full::new(Bytes::from(err.to_string()))
-                            .map_err(|_: Infallible| unreachable!())
-                            .boxed(),
-                    )?)
+                let err = ProxyError::from(err);
+                if err.kind().status().is_server_error() {
+                    log::error!("[{id}] Reverse-proxy forwarding error: {err}");
+                } else {
+                    log::debug!("[{id}] Reverse-proxy rejected request: {err}");
+                }
+                Ok(err.into_response())
             }
         }
     }
 }
 
 struct RequestParams {
+    id: RequestId,
+    accepted: Vec<Encoding>,
     streaming: bool,
+    shutdown: Option<Shutdown>,
 }
 
 impl ReverseProxy {
-    async fn forward(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
-        log::trace!("Incoming: {req:?}");
+    async fn forward(
+        &self,
+        req: Request<Incoming>,
+        id: &RequestId,
+    ) -> Result<Response<Outgoing>, Error> {
+        log::trace!("[{id}] Incoming: {req:?}");
 
-        let (req, params) = self.convert_request(req).await?;
-        log::trace!("Outgoing: {req:?}");
+        let (req, params) = self.convert_request(req, id).await?;
+        log::trace!("[{id}] Outgoing: {req:?}");
 
         // Await the response...
-        let res = self.send(req).await?;
-        log::trace!("Outgoing: {res:?}");
+        let send = self.pool.send(req);
+        let res = match self.response_timeout {
+            Some(duration) => timeout(duration, send).await.map_err(|_| {
+                ProxyError::new(
+                    ErrorKind::GatewayTimeout,
+                    format!("Upstream server didn't respond in {duration:?}"),
+                )
+            })?,
+            None => send.await,
+        }
+        .map_err(|err| {
+            log::error!("[{id}] Upstream request failed: {err:#}");
+            ProxyError::bad_gateway("Upstream service unavailable")
+        })?;
+        log::trace!("[{id}] Outgoing: {res:?}");
 
         let res = self.convert_response(res, params).await?;
-        log::trace!("Incoming: {res:?}");
+        log::trace!("[{id}] Incoming: {res:?}");
 
         Ok(res)
     }
@@ -234,21 +238,44 @@ impl ReverseProxy {
     async fn convert_request(
         &self,
         req: Request<Incoming>,
+        id: &RequestId,
     ) -> Result<(Request<Full<Bytes>>, RequestParams), Error> {
         let host = self.url.authority().expect("Client URL must be set");
         let uri = req.uri().clone();
-        if uri.path() != "/chat/completions" {
-            bail!("Path must be '/chat/completions' but got {:?}", uri.path());
-        }
-        let data = req.into_body().collect().await?.to_bytes();
-        log::trace!("Incoming request data: {}", String::from_utf8_lossy(&data));
-        let msg: api::Request = serde_json::from_slice(&data)?;
+        let shutdown = req.extensions().get::<Shutdown>().cloned();
+        let accepted = Encoding::accepted(req.headers());
+        let data = match Limited::new(req.into_body(), self.max_request_size)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(err) if err.is::<LengthLimitError>() => {
+                return Err(ProxyError::new(
+                    ErrorKind::PayloadTooLarge,
+                    format!(
+                        "Request body is larger than {} bytes",
+                        self.max_request_size
+                    ),
+                )
+                .into());
+            }
+            Err(err) => bail!(ProxyError::bad_request(format!(
+                "Cannot read request body: {err}"
+            ))),
+        };
+        log::trace!(
+            "[{id}] Incoming request data: {}",
+            String::from_utf8_lossy(&data)
+        );
+        let msg: api::Request = serde_json::from_slice(&data)
+            .map_err(|err| ProxyError::bad_request(format!("Invalid request body: {err}")))?;
 
         let mut messages = vec![];
         if let Some(prompt) = &self.system_prompt {
             messages.push(Message {
                 role: "system".into(),
-                content: prompt.into(),
+                content: Some(Content::Text(prompt.into())),
+                extra: Default::default(),
             });
         }
         messages.extend(msg.messages);
@@ -257,11 +284,12 @@ impl ReverseProxy {
             model: self.model.as_str().into(),
             messages,
             stream: Some(streaming),
+            extra: msg.extra,
         };
 
         let data = Bytes::from(serde_json::to_vec(&msg)?);
         log::trace!(
-            "Outgoing request data: {:?}",
+            "[{id}] Outgoing request data: {:?}",
             String::from_utf8_lossy(&data)
         );
 
@@ -269,7 +297,7 @@ impl ReverseProxy {
             let mut parts = uri.into_parts();
             parts.path_and_query = Some(PathAndQuery::from_static(match self.kind {
                 ServerKind::LlamaCpp => "/chat/completions",
-                ServerKind::OpenAi { .. } => "/v1/chat/completions",
+                ServerKind::OpenAi => "/v1/chat/completions",
             }));
             Uri::from_parts(parts)?
         };
@@ -278,12 +306,21 @@ impl ReverseProxy {
             .uri(&uri)
             .header(header::HOST, host.as_str())
             .header(header::ACCEPT, "application/json")
-            .header(header::CONTENT_TYPE, "application/json");
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(X_REQUEST_ID, id.header_value());
         if let Some(api_key) = &self.api_key {
             builder = builder.header(header::AUTHORIZATION, format!("Bearer {api_key}"));
         }
 
-        Ok((builder.body(Full::new(data))?, RequestParams { streaming }))
+        Ok((
+            builder.body(Full::new(data))?,
+            RequestParams {
+                id: id.clone(),
+                accepted,
+                streaming,
+                shutdown,
+            },
+        ))
     }
 
     async fn convert_response(
@@ -292,82 +329,538 @@ impl ReverseProxy {
         params: RequestParams,
     ) -> Result<Response<Outgoing>, Error> {
         if !res.status().is_success() {
-            bail!("Response status is {}", res.status());
+            return self.convert_error(res, &params.id).await;
         }
+        let upstream_id = res.headers().get(X_REQUEST_ID).cloned();
 
         let body = if params.streaming {
             let mut event_reader = EventReader::default();
-            StreamBody::new(BodyStream::new(res.into_body()).map(move |res| {
-                let input = match res?.into_data() {
-                    Ok(data) => data.to_vec(),
-                    Err(frame) => return Ok(frame),
-                };
-                log::trace!(
-                    "Outgoing response data frame: {}",
-                    String::from_utf8_lossy(&input)
-                );
+            // Number of content chunks sent to the client, roughly the number of tokens.
+            let delivered = Arc::new(AtomicUsize::new(0));
+            let id = params.id.clone();
+            let counter = delivered.clone();
+            let stream = BodyStream::new(res.into_body()).map(move |res| {
                 let mut output = String::new();
-
-                const DONE: &str = "[DONE]";
-                for event in event_reader.next_events(&input)? {
-                    let data = match event.data {
-                        Some(data) => data,
-                        None => continue,
-                    };
-
-                    let event = if data == DONE {
-                        Event {
-                            data: Some(DONE.into()),
-                            ..Default::default()
-                        }
-                    } else {
-                        let msg: api::ResponseStreamChunk = serde_json::from_str(&data)?;
-
-                        let mut choices = msg.choices;
-                        for choice in choices.iter_mut() {
-                            if choice.finish_reason.is_some() && choice.delta.role.is_none() {
-                                choice.delta.role = Some("assistant".into());
-                            }
-                        }
-                        let msg = api::ResponseStreamChunk { choices };
-                        Event {
-                            // TODO: Write to output without allocation
-                            data: Some(serde_json::to_string(&msg)?.into()),
-                            ..Default::default()
+                let res = match res {
+                    Ok(frame) => match frame.into_data() {
+                        Ok(input) => {
+                            log::trace!(
+                                "[{id}] Outgoing response data frame: {}",
+                                String::from_utf8_lossy(&input)
+                            );
+                            convert_events(&mut event_reader, &input, &mut output, &counter)
                         }
+                        Err(frame) => return Ok(frame),
+                    },
+                    Err(err) => Err(ProxyError::bad_gateway(format!(
+                        "Upstream response stream failed: {err}"
+                    ))
+                    .into()),
+                };
+                match res {
+                    Ok(()) => {
+                        log::trace!("[{id}] Incoming response data frame: {output}");
+                        Ok(Frame::data(Bytes::from(output)))
+                    }
+                    // Events converted before the error are still sent
+                    Err(err) => Err((output, err)),
+                }
+            });
+            let stream = EndOnError::new(stream, {
+                let id = params.id.clone();
+                let delivered = delivered.clone();
+                move |(output, err): (String, Error)| {
+                    let err = ProxyError::from(err);
+                    log::error!(
+                        "[{id}] Response stream failed after {} tokens: {err}",
+                        delivered.load(Ordering::Relaxed)
+                    );
+                    let mut data = output.into_bytes();
+                    data.extend_from_slice(&error_events(&err));
+                    Frame::data(Bytes::from(data))
+                }
+            });
+            match params.shutdown {
+                Some(shutdown) => {
+                    let id = params.id.clone();
+                    let aborted = async move {
+                        shutdown.aborted().await;
+                        log::warn!(
+                            "[{id}] Aborting response stream due to shutdown after {} tokens",
+                            delivered.load(Ordering::Relaxed)
+                        );
+                        let err =
+                            ProxyError::new(ErrorKind::Unavailable, "Server is shutting down")
+                                .code("shutdown");
+                        Ok(Frame::data(error_events(&err)))
                     };
-
-                    event.write_to(&mut output)?;
+                    StreamBody::new(EndWith::new(stream, aborted))
+                        .map_err(|_: Infallible| unreachable!())
+                        .boxed()
                 }
-
-                log::trace!("Incoming response data frame: {}", output);
-                Ok(Frame::data(Bytes::from(output)))
-            }))
-            .boxed()
+                None => StreamBody::new(stream)
+                    .map_err(|_: Infallible| unreachable!())
+                    .boxed(),
+            }
         } else {
-            let data = res.into_body().collect().await?.to_bytes();
-            log::trace!("Outgoing response data: {}", String::from_utf8_lossy(&data));
-            let msg: api::Response = serde_json::from_slice(&data)?;
-
-            let msg = api::Response {
-                choices: msg.choices,
-            };
-            log::trace!("Incoming response struct: {:?}", msg);
+            let id = &params.id;
+            let data = res
+                .into_body()
+                .collect()
+                .await
+                .map_err(|err| {
+                    log::error!("[{id}] Cannot read upstream response: {err:#}");
+                    ProxyError::bad_gateway("Cannot read upstream response")
+                })?
+                .to_bytes();
+            log::trace!(
+                "[{id}] Outgoing response data: {}",
+                String::from_utf8_lossy(&data)
+            );
+            let msg: api::Response = serde_json::from_slice(&data).map_err(|err| {
+                ProxyError::bad_gateway(format!("Invalid upstream response: {err}"))
+            })?;
+            log::trace!("[{id}] Incoming response struct: {msg:?}");
             let data = serde_json::to_string(&msg)?;
             Full::new(Bytes::from(data))
                 .map_err(|_: Infallible| unreachable!())
                 .boxed()
         };
 
-        Ok(Response::builder()
-            .header(
+        let mut builder = Response::builder().header(
+            header::CONTENT_TYPE,
+            if params.streaming {
+                "text/event-stream"
+            } else {
+                "application/json"
+            },
+        );
+        if let Some(upstream_id) = upstream_id {
+            builder = builder.header(X_UPSTREAM_REQUEST_ID, upstream_id);
+        }
+        let res = builder.body(body)?;
+        Ok(match &self.compression {
+            Some(compression) => compression.compress(&params.accepted, res),
+            None => res,
+        })
+    }
+}
+
+impl ReverseProxy {
+    /// Pass upstream error response to the client with its status and rate limit headers.
+    ///
+    /// Bodies larger than 64 KiB are forwarded as is without normalization.
+    async fn convert_error(
+        &self,
+        res: Response<Incoming>,
+        id: &RequestId,
+    ) -> Result<Response<Outgoing>, Error> {
+        const MAX_ERROR_SIZE: usize = 64 << 10;
+
+        let (parts, mut body) = res.into_parts();
+        let status = parts.status;
+        let mut builder = Response::builder().status(status);
+        for (name, value) in &parts.headers {
+            if name == header::RETRY_AFTER || name.as_str().starts_with("x-ratelimit-") {
+                builder = builder.header(name, value);
+            } else if name == X_REQUEST_ID {
+                builder = builder.header(X_UPSTREAM_REQUEST_ID, value);
+            }
+        }
+        let content_type = parts.headers.get(header::CONTENT_TYPE);
+
+        let mut data = Vec::new();
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(|err| {
+                log::error!("[{id}] Cannot read upstream error response: {err:#}");
+                ProxyError::bad_gateway("Cannot read upstream error response")
+            })?;
+            let Ok(chunk) = frame.into_data() else {
+                continue;
+            };
+            data.extend_from_slice(&chunk);
+            if data.len() > MAX_ERROR_SIZE {
+                log::warn!(
+                    "[{id}] Upstream server responded with {status} and error body larger than {MAX_ERROR_SIZE} bytes"
+                );
+                if let Some(content_type) = content_type {
+                    builder = builder.header(header::CONTENT_TYPE, content_type);
+                }
+                let head = tokio_stream::once(Ok(Frame::data(Bytes::from(data))));
+                let rest = BodyStream::new(body).map(|frame| frame.map_err(Error::from));
+                return Ok(builder.body(StreamBody::new(head.chain(rest)).boxed())?);
+            }
+        }
+        let data = Bytes::from(data);
+        if status.is_server_error() {
+            log::warn!(
+                "[{id}] Upstream server responded with {status}: {}",
+                String::from_utf8_lossy(&data)
+            );
+        } else {
+            log::debug!(
+                "[{id}] Upstream server responded with {status}: {}",
+                String::from_utf8_lossy(&data)
+            );
+        }
+
+        let data = if !self.normalize_errors
+            || serde_json::from_slice::<api::ErrorResponse>(&data).is_ok()
+        {
+            if let Some(content_type) = content_type {
+                builder = builder.header(header::CONTENT_TYPE, content_type);
+            }
+            data
+        } else {
+            builder = builder.header(
                 header::CONTENT_TYPE,
-                if params.streaming {
-                    "text/event-stream"
-                } else {
-                    "application/json"
-                },
-            )
-            .body(body)?)
+                HeaderValue::from_static("application/json"),
+            );
+            Bytes::from(serde_json::to_vec(&normalize_error(status, &data))?)
+        };
+        Ok(builder.body(
+            Full::new(data)
+                .map_err(|_: Infallible| unreachable!())
+                .boxed(),
+        )?)
+    }
+}
+
+/// Error in OpenAI format made from arbitrary upstream error body.
+///
+/// Message and type are taken from `{"error": {"message", "type"}}`, `{"error": "..."}`
+/// or the plain text body when present.
+fn normalize_error(status: StatusCode, data: &[u8]) -> api::ErrorResponse<'static> {
+    let value = serde_json::from_slice::<Value>(data).ok();
+    let error = value.as_ref().map(|v| v.get("error").unwrap_or(v));
+    let field = |name: &str| {
+        error
+            .and_then(|e| e.get(name))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    };
+    let text = String::from_utf8_lossy(data).trim().to_string();
+    let message = field("message")
+        .or_else(|| error.and_then(Value::as_str).map(str::to_string))
+        .or_else(|| Some(text).filter(|text| value.is_none() && !text.is_empty()))
+        .unwrap_or_else(|| format!("Upstream server responded with {status}"));
+    api::ErrorResponse {
+        error: api::ApiError {
+            message: message.into(),
+            type_: field("type")
+                .map(Into::into)
+                .unwrap_or(ErrorKind::from_status(status).type_().into()),
+            // llama.cpp reports HTTP status as numeric code.
+            code: error
+                .and_then(|e| e.get("code"))
+                .and_then(|code| match code {
+                    Value::String(code) => Some(code.clone()),
+                    Value::Number(code) => Some(code.to_string()),
+                    _ => None,
+                })
+                .map(Into::into),
+        },
+    }
+}
+
+/// Convert upstream SSE events from `input` and write them to `output`.
+///
+/// `delivered` is incremented for each chunk with content.
+fn convert_events(
+    event_reader: &mut EventReader,
+    input: &[u8],
+    output: &mut String,
+    delivered: &AtomicUsize,
+) -> Result<(), Error> {
+    const DONE: &str = "[DONE]";
+    let events = event_reader
+        .next_events(input)
+        .map_err(|err| ProxyError::bad_gateway(format!("Invalid upstream event stream: {err}")))?;
+    for event in events {
+        let data = match event.data {
+            Some(data) => data,
+            None => continue,
+        };
+
+        let event = if data == DONE {
+            Event {
+                data: Some(DONE.into()),
+                ..Default::default()
+            }
+        } else {
+            let msg: api::ResponseStreamChunk = serde_json::from_str(&data).map_err(|err| {
+                ProxyError::bad_gateway(format!("Invalid upstream response chunk: {err}"))
+            })?;
+
+            let mut choices = msg.choices;
+            for choice in choices.iter_mut() {
+                if choice.delta.content.as_ref().is_some_and(|c| !c.is_empty()) {
+                    delivered.fetch_add(1, Ordering::Relaxed);
+                }
+                if choice.finish_reason.is_some() && choice.delta.role.is_none() {
+                    choice.delta.role = Some("assistant".into());
+                }
+            }
+            let msg = api::ResponseStreamChunk {
+                choices,
+                extra: msg.extra,
+            };
+            Event {
+                // TODO: Write to output without allocation
+                data: Some(serde_json::to_string(&msg)?.into()),
+                ..Default::default()
+            }
+        };
+
+        event.write_to(output)?;
     }
+    Ok(())
+}
+
+/// SSE events that contain OpenAI error object and terminate the stream.
+fn error_events(err: &ProxyError) -> Bytes {
+    let mut output = String::new();
+    for data in [
+        serde_json::to_string(&err.to_api()).expect("Error serialization cannot fail"),
+        "[DONE]".to_string(),
+    ] {
+        let event = Event {
+            data: Some(data.into()),
+            ..Default::default()
+        };
+        event
+            .write_to(&mut output)
+            .expect("Writing to string cannot fail");
+    }
+    Bytes::from(output)
+}
+
+/// Stream that is cut short when `end` resolves, yielding its output as the last item.
+#[pin_project]
+struct EndWith<S, F> {
+    #[pin]
+    stream: S,
+    #[pin]
+    end: F,
+    done: bool,
+}
+
+impl<S, F> EndWith<S, F> {
+    fn new(stream: S, end: F) -> Self {
+        Self {
+            stream,
+            end,
+            done: false,
+        }
+    }
+}
+
+impl<S: Stream, F: Future<Output = S::Item>> Stream for EndWith<S, F> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(item) = this.end.poll(cx) {
+            *this.done = true;
+            return Poll::Ready(Some(item));
+        }
+        let poll = this.stream.poll_next(cx);
+        if let Poll::Ready(None) = poll {
+            *this.done = true;
+        }
+        poll
+    }
+}
+
+/// Stream that ends after the first error, yielding the output of `recover` for it instead.
+///
+/// The resulting stream never fails.
+#[pin_project]
+struct EndOnError<S, F> {
+    #[pin]
+    stream: S,
+    recover: F,
+    done: bool,
+}
+
+impl<S, F> EndOnError<S, F> {
+    fn new(stream: S, recover: F) -> Self {
+        Self {
+            stream,
+            recover,
+            done: false,
+        }
+    }
+}
+
+impl<T, E, S: Stream<Item = Result<T, E>>, F: FnMut(E) -> T> Stream for EndOnError<S, F> {
+    type Item = Result<T, Infallible>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+        match this.stream.poll_next(cx) {
+            Poll::Ready(Some(Err(err))) => {
+                *this.done = true;
+                Poll::Ready(Some(Ok((this.recover)(err))))
+            }
+            Poll::Ready(Some(Ok(item))) => Poll::Ready(Some(Ok(item))),
+            Poll::Ready(None) => {
+                *this.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[tokio::test]
+async fn end_with() {
+    let (send, recv) = tokio::sync::oneshot::channel::<()>();
+    let stream = tokio_stream::iter([1, 2]).chain(tokio_stream::pending());
+    let mut stream = Box::pin(EndWith::new(stream, async move {
+        recv.await.unwrap();
+        3
+    }));
+    assert_eq!(stream.next().await, Some(1));
+    assert_eq!(stream.next().await, Some(2));
+    send.send(()).unwrap();
+    assert_eq!(stream.next().await, Some(3));
+    assert_eq!(stream.next().await, None);
+}
+
+#[tokio::test]
+async fn upstream_errors() {
+    use serde_json::json;
+
+    use crate::{Server, server::test_request};
+
+    /// Upstream that fails differently depending on the message content.
+    #[derive(Clone)]
+    struct Upstream;
+    impl Service for Upstream {
+        async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+            let body = req.into_body().collect().await?.to_bytes();
+            let req: api::Request = serde_json::from_slice(&body)?;
+            let content = match &req.messages[0].content {
+                Some(Content::Text(text)) => text.as_ref(),
+                _ => "",
+            };
+            let (status, body) = match content {
+                "openai" => (
+                    StatusCode::BAD_REQUEST,
+                    json!({"error": {"message": "context length exceeded", "type": "invalid_request_error", "param": "messages", "code": "context_length_exceeded"}}).to_string(),
+                ),
+                "llama" => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    json!({"error": {"code": 503, "message": "Loading model", "type": "unavailable_error"}}).to_string(),
+                ),
+                "stream" => (
+                    StatusCode::OK,
+                    format!(
+                        "data: {}\n\ndata: {{broken\n\n",
+                        json!({"choices": [{"delta": {"content": "hi"}, "index": 0, "finish_reason": null}]})
+                    ),
+                ),
+                "large" => (StatusCode::INTERNAL_SERVER_ERROR, "x".repeat(100 << 10)),
+                _ => (StatusCode::TOO_MANY_REQUESTS, "slow down\n".to_string()),
+            };
+            Ok(Response::builder()
+                .status(status)
+                .header(header::RETRY_AFTER, "5")
+                .header("x-ratelimit-remaining-requests", "0")
+                .header("x-internal", "1")
+                .body(Full::from(body).map_err(Error::new).boxed())?)
+        }
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let server = Server::new().bind_listener(listener).unwrap();
+    tokio::spawn(server.serve(async || Ok(Upstream)));
+
+    let proxy = Arc::new(ReverseProxy::new(url.parse().unwrap()));
+    let request = async |content: &str| {
+        let body = json!({"model": "m", "messages": [{"role": "user", "content": content}], "stream": content == "stream"});
+        let req = Request::post("/chat/completions")
+            .header(X_REQUEST_ID, "client-1")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap();
+        let res = test_request(proxy.clone(), req).await;
+        let body = serde_json::from_slice::<Value>(res.body()).unwrap_or_default();
+        (res, body)
+    };
+
+    // Broken chunk ends the stream with an error event
+    let (res, _) = request("stream").await;
+    assert_eq!(res.status(), 200);
+    let events = EventReader::default()
+        .next_events(res.body())
+        .unwrap()
+        .map(|event| event.data.unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(events.len(), 3);
+    assert_eq!(
+        serde_json::from_str::<Value>(&events[0]).unwrap()["choices"][0]["delta"]["content"],
+        "hi"
+    );
+    let error = serde_json::from_str::<Value>(&events[1]).unwrap();
+    assert_eq!(error["error"]["type"], "server_error");
+    assert_eq!(events[2], "[DONE]");
+
+    // OpenAI errors are passed as is
+    let (res, body) = request("openai").await;
+    assert_eq!(res.status(), 400);
+    assert_eq!(body["error"]["param"], "messages");
+    assert_eq!(body["error"]["code"], "context_length_exceeded");
+
+    let (res, body) = request("llama").await;
+    assert_eq!(res.status(), 503);
+    assert_eq!(
+        body,
+        json!({"error": {"message": "Loading model", "type": "unavailable_error", "code": "503"}})
+    );
+
+    // Large error bodies are forwarded without normalization
+    let (res, _) = request("large").await;
+    assert_eq!(res.status(), 500);
+    assert_eq!(res.body().len(), 100 << 10);
+    assert_eq!(res.headers()[header::RETRY_AFTER], "5");
+
+    let (res, body) = request("text").await;
+    assert_eq!(res.status(), 429);
+    assert_eq!(res.headers()[header::RETRY_AFTER], "5");
+    assert_eq!(res.headers()["x-ratelimit-remaining-requests"], "0");
+    assert!(!res.headers().contains_key("x-internal"));
+    assert_eq!(res.headers()[X_REQUEST_ID], "client-1");
+    // Upstream is our server too, so it echoes the forwarded ID
+    assert_eq!(res.headers()[X_UPSTREAM_REQUEST_ID], "client-1");
+    assert_eq!(body["error"]["message"], "slow down");
+    assert_eq!(body["error"]["type"], "rate_limit_error");
+}
+
+#[tokio::test]
+async fn upstream_unreachable() {
+    use crate::server::test_request;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    drop(listener);
+
+    let proxy = Arc::new(ReverseProxy::new(url.parse().unwrap()));
+    let req = Request::post("/chat/completions")
+        .body(Full::new(Bytes::from(
+            r#"{"model": "m", "messages": [{"role": "user", "content": "hi"}]}"#,
+        )))
+        .unwrap();
+    let res = test_request(proxy, req).await;
+    assert_eq!(res.status(), 502);
+    let body = serde_json::from_slice::<Value>(res.body()).unwrap();
+    // Connection details are logged, not sent to the client
+    assert_eq!(body["error"]["message"], "Upstream service unavailable");
 }
@@ -0,0 +1,161 @@
+//! [HAProxy PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) v1 and v2.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Error, bail};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+/// Maximum length of v1 header including CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read PROXY protocol header from the beginning of `stream`.
+///
+/// Only the header is consumed, so the stream may be passed to TLS or HTTP afterwards.
+/// Returns the original client address, or `None` if the proxy didn't provide it
+/// (e.g. for its own health checks).
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, Error> {
+    let mut prefix = [0; V1_PREFIX.len()];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..prefix.len()] {
+        read_v2(stream).await
+    } else {
+        bail!("Connection doesn't start with PROXY protocol header")
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, Error> {
+    // Header is small and must not be over-read, so we read it byte by byte.
+    let mut line = Vec::from(V1_PREFIX);
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("PROXY protocol v1 header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = str::from_utf8(&line[..line.len() - 2])?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse()?;
+            if ip.is_ipv4() != (fields[1] == "TCP4") {
+                bail!("PROXY protocol v1 address doesn't match protocol: {line:?}");
+            }
+            Ok(Some(SocketAddr::new(ip, src_port.parse()?)))
+        }
+        _ => bail!("Invalid PROXY protocol v1 header: {line:?}"),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, Error> {
+    let mut header = [0; 16];
+    header[..V1_PREFIX.len()].copy_from_slice(&V2_SIGNATURE[..V1_PREFIX.len()]);
+    stream.read_exact(&mut header[V1_PREFIX.len()..]).await?;
+    if &header[..V2_SIGNATURE.len()] != V2_SIGNATURE {
+        bail!("Invalid PROXY protocol v2 signature");
+    }
+    let (version, command, family) = (header[12] >> 4, header[12] & 0xf, header[13]);
+    if version != 2 {
+        bail!("Unsupported PROXY protocol version: {version}");
+    }
+    // Addresses are followed by optional TLVs which we skip.
+    let mut payload = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream.read_exact(&mut payload).await?;
+
+    match command {
+        // LOCAL, connection is established by the proxy itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => (),
+        _ => bail!("Unsupported PROXY protocol v2 command: {command}"),
+    }
+    let addr = match family {
+        // TCP over IPv4
+        0x11 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[0..4]).unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([payload[8], payload[9]]))
+        }
+        // TCP over IPv6
+        0x21 if payload.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[0..16]).unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([payload[32], payload[33]]))
+        }
+        0x11 | 0x21 => bail!("PROXY protocol v2 address block is too short"),
+        // Unspecified, UDP or Unix sockets
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+#[tokio::test]
+async fn v1() {
+    let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+    let addr = read_header(&mut stream).await.unwrap();
+    assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(stream, b"GET /");
+
+    let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+    let addr = read_header(&mut stream).await.unwrap();
+    assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+    let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+    assert_eq!(read_header(&mut stream).await.unwrap(), None);
+
+    for header in [
+        &b"GET / HTTP/1.1\r\n"[..],
+        b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n",
+        b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+        &[&b"PROXY "[..], &[b'x'; 128]].concat(),
+    ] {
+        assert!(read_header(&mut &header[..]).await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn v2() {
+    let header = |command: u8, family: u8, addrs: &[u8]| {
+        let mut header = Vec::from(V2_SIGNATURE);
+        header.extend([0x20 | command, family]);
+        header.extend((addrs.len() as u16).to_be_bytes());
+        header.extend(addrs);
+        header.extend(b"GET /");
+        header
+    };
+
+    let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+    let data = header(0x1, 0x11, &ipv4);
+    let mut stream = &data[..];
+    let addr = read_header(&mut stream).await.unwrap();
+    assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(stream, b"GET /");
+
+    let mut ipv6 = Vec::new();
+    ipv6.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+    ipv6.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+    ipv6.extend([0xdc, 0x04, 0x01, 0xbb]);
+    // TLV is skipped
+    ipv6.extend([0x04, 0x00, 0x01, 0x00]);
+    let data = header(0x1, 0x21, &ipv6);
+    let mut stream = &data[..];
+    let addr = read_header(&mut stream).await.unwrap();
+    assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    assert_eq!(stream, b"GET /");
+
+    let data = header(0x0, 0x00, &[]);
+    let mut stream = &data[..];
+    assert_eq!(read_header(&mut stream).await.unwrap(), None);
+    assert_eq!(stream, b"GET /");
+
+    assert!(
+        read_header(&mut &header(0x1, 0x11, &ipv4[..8])[..])
+            .await
+            .is_err()
+    );
+}
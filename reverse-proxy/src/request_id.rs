@@ -0,0 +1,71 @@
+//! Request IDs used to correlate log lines, upstream requests and responses.
+
+use std::fmt;
+
+use http::{HeaderMap, HeaderName, HeaderValue, Request};
+use uuid::Uuid;
+
+/// Header carrying the request ID both in requests and responses.
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+/// Response header carrying the ID assigned to the request by the upstream server.
+pub const X_UPSTREAM_REQUEST_ID: HeaderName = HeaderName::from_static("x-upstream-request-id");
+
+/// Maximum length of inbound request ID to be reused.
+const MAX_LEN: usize = 128;
+
+/// ID of the request, stored in request extensions by the server and echoed in the response.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct RequestId(HeaderValue);
+
+impl RequestId {
+    /// Random UUID.
+    pub fn generate() -> Self {
+        let id = Uuid::new_v4().to_string();
+        Self(HeaderValue::from_str(&id).expect("UUID is a valid header value"))
+    }
+
+    /// Reuse inbound `x-request-id` if it is sane, otherwise generate a new one.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        match headers.get(X_REQUEST_ID) {
+            Some(value)
+                if (1..=MAX_LEN).contains(&value.len())
+                    && value.as_bytes().iter().all(u8::is_ascii_graphic) =>
+            {
+                Self(value.clone())
+            }
+            _ => Self::generate(),
+        }
+    }
+
+    /// ID stored in request extensions, `None` if the request didn't come through the server.
+    pub fn get<B>(req: &Request<B>) -> Option<&Self> {
+        req.extensions().get()
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().expect("Request ID is visible ASCII")
+    }
+
+    pub fn header_value(&self) -> &HeaderValue {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[test]
+fn from_headers() {
+    let mut headers = HeaderMap::new();
+    let generated = RequestId::from_headers(&headers);
+    assert_eq!(generated.as_str().len(), 36);
+    assert_ne!(generated, RequestId::from_headers(&headers));
+
+    headers.insert(X_REQUEST_ID, HeaderValue::from_static("abc-123"));
+    assert_eq!(RequestId::from_headers(&headers).as_str(), "abc-123");
+    headers.insert(X_REQUEST_ID, HeaderValue::from_static("with space"));
+    assert_ne!(RequestId::from_headers(&headers).as_str(), "with space");
+}
@@ -0,0 +1,524 @@
+use std::{borrow::Cow, sync::Arc};
+
+use anyhow::Error;
+use http::{
+    HeaderValue, Method, Request, Response, Uri, header,
+    uri::{Authority, PathAndQuery},
+};
+use hyper::body::Incoming;
+use regex::Regex;
+
+use crate::{
+    Outgoing, Service,
+    error::{ErrorKind, ProxyError},
+    request_id::RequestId,
+    service::ServiceDyn,
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Segment {
+    Literal(String),
+    /// `{name}`, matches any single segment.
+    Param(String),
+}
+
+/// Conditions for a request to be passed to a service in [`Router`].
+///
+/// Path pattern consists of segments separated by `/`, segment `{name}` matches any single segment
+/// and its value is stored in [`PathParams`].
+#[derive(Clone, Debug)]
+pub struct Route {
+    segments: Vec<Segment>,
+    prefix: bool,
+    methods: Vec<Method>,
+    host: Option<String>,
+    strip: bool,
+}
+
+/// Route specificity, more specific routes take precedence.
+///
+/// Compared field by field: routes for a specific host, then longer paths,
+/// then exact paths over prefixes, then literal segments over parameters.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct Score {
+    host: bool,
+    len: usize,
+    exact: bool,
+    literals: usize,
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+impl Route {
+    /// Match the whole path, e.g. `/v1/models/{id}`.
+    pub fn new(pattern: &str) -> Self {
+        Self::parse(pattern, false)
+    }
+
+    /// Match the path and everything below it, e.g. `/static` matches `/static/app.js`, but not `/static-app.js`.
+    pub fn prefix(pattern: &str) -> Self {
+        Self::parse(pattern.trim_end_matches('/'), true)
+    }
+
+    fn parse(pattern: &str, prefix: bool) -> Self {
+        let segments = if prefix && pattern.is_empty() {
+            Vec::new()
+        } else {
+            split_path(pattern)
+                .map(
+                    |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                        Some(name) => Segment::Param(name.to_string()),
+                        None => Segment::Literal(segment.to_string()),
+                    },
+                )
+                .collect()
+        };
+        Self {
+            segments,
+            prefix,
+            methods: Vec::new(),
+            host: None,
+            strip: false,
+        }
+    }
+
+    /// Remove matched prefix from the path before passing request to the service.
+    ///
+    /// Original URI is kept in [`OriginalUri`] extension.
+    pub fn strip_prefix(mut self) -> Self {
+        self.strip = self.prefix;
+        self
+    }
+
+    /// Allow request method, all methods are allowed if none are specified.
+    ///
+    /// `GET` also allows `HEAD`.
+    pub fn method(mut self, method: Method) -> Self {
+        self.methods.push(method);
+        self
+    }
+
+    /// Match requests to `host` only, wildcard like `*.example.com` matches all subdomains.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into().to_ascii_lowercase());
+        self
+    }
+
+    fn score(&self) -> Score {
+        Score {
+            host: self.host.is_some(),
+            len: self.segments.len(),
+            exact: !self.prefix,
+            literals: self
+                .segments
+                .iter()
+                .filter(|s| matches!(s, Segment::Literal(_)))
+                .count(),
+        }
+    }
+
+    fn match_host(&self, host: Option<&str>) -> bool {
+        let (pattern, host) = match (&self.host, host) {
+            (None, _) => return true,
+            (Some(pattern), Some(host)) => (pattern, host.to_ascii_lowercase()),
+            (Some(_), None) => return false,
+        };
+        match pattern.strip_prefix("*.") {
+            Some(parent) => host
+                .split_once('.')
+                .is_some_and(|(_, host_parent)| host_parent == parent),
+            None => *pattern == host,
+        }
+    }
+
+    fn match_path(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut parts = split_path(path);
+        for segment in &self.segments {
+            let part = parts.next()?;
+            match segment {
+                Segment::Literal(literal) => {
+                    if part != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    if part.is_empty() {
+                        return None;
+                    }
+                    params.push((name.clone(), part.to_string()));
+                }
+            }
+        }
+        if !self.prefix && parts.next().is_some() {
+            return None;
+        }
+        Some(params)
+    }
+
+    fn match_method(&self, method: &Method) -> bool {
+        self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|m| m == method || (*m == Method::GET && *method == Method::HEAD))
+    }
+}
+
+/// Values of `{name}` segments of the matched route, stored in request extensions.
+#[derive(Clone, Default, Debug)]
+pub struct PathParams {
+    params: Vec<(String, String)>,
+}
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+/// URI of the request before it was changed by [`Router`] rewrites or mounts, stored in request extensions.
+#[derive(Clone, Debug)]
+pub struct OriginalUri(pub Uri);
+
+/// Replace path of the request URI keeping the query.
+fn set_path<B>(req: &mut Request<B>, path: &str) -> Result<(), Error> {
+    let uri = req.uri().clone();
+    req.extensions_mut()
+        .get_or_insert_with(|| OriginalUri(uri.clone()));
+    let mut parts = uri.into_parts();
+    let query = parts.path_and_query.as_ref().and_then(|pq| pq.query());
+    parts.path_and_query = Some(PathAndQuery::try_from(match query {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    })?);
+    *req.uri_mut() = Uri::from_parts(parts)?;
+    Ok(())
+}
+
+/// Host the request is sent to, without port.
+fn request_host<B>(req: &Request<B>) -> Option<&str> {
+    match req.uri().host() {
+        Some(host) => Some(host),
+        None => {
+            let host = req.headers().get(header::HOST)?.to_str().ok()?;
+            // Authority is parsed to properly strip port from IPv6 addresses
+            let authority = host.parse::<Authority>().ok()?;
+            let port_len = authority.port().map_or(0, |p| p.as_str().len() + 1);
+            Some(&host[..host.len() - port_len])
+        }
+    }
+}
+
+/// Dispatches requests to services by method, path and host.
+///
+/// The most specific route matching the request is chosen regardless of insertion order, see [`Route`].
+/// Requests that match no route are passed to the default service,
+/// and requests that match routes only by path get `405 Method Not Allowed`.
+pub struct Router {
+    routes: Vec<(Route, Arc<dyn ServiceDyn>)>,
+    default: Arc<dyn ServiceDyn>,
+    rewrites: Vec<(Regex, String)>,
+}
+
+impl Router {
+    pub fn new<S: Service + 'static>(default: S) -> Self {
+        Self {
+            routes: Vec::new(),
+            default: Arc::new(default),
+            rewrites: Vec::new(),
+        }
+    }
+
+    /// Add service for all requests with path starting with `prefix`.
+    pub fn push<S: Service + 'static>(self, prefix: &str, service: S) -> Self {
+        self.route(Route::prefix(prefix), service)
+    }
+
+    pub fn route<S: Service + 'static>(mut self, route: Route, service: S) -> Self {
+        self.routes.push((route, Arc::new(service)));
+        self
+    }
+
+    /// Add service for all requests with path starting with `prefix`, the prefix is removed from the path.
+    ///
+    /// E.g. service mounted at `/llm` gets `/chat/completions` for `/llm/chat/completions`.
+    pub fn mount<S: Service + 'static>(self, prefix: &str, service: S) -> Self {
+        self.route(Route::prefix(prefix).strip_prefix(), service)
+    }
+
+    /// Rewrite request path before dispatching, `replacement` may refer to capture groups like `$1`.
+    ///
+    /// Rules are applied in order they are added, each to the result of the previous one.
+    pub fn rewrite(mut self, regex: Regex, replacement: impl Into<String>) -> Self {
+        self.rewrites.push((regex, replacement.into()));
+        self
+    }
+}
+
+impl Service for Router {
+    async fn call(&self, mut req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+        let mut path = Cow::Borrowed(req.uri().path());
+        for (regex, replacement) in &self.rewrites {
+            if let Cow::Owned(rewritten) = regex.replace(&path, replacement) {
+                path = Cow::Owned(rewritten);
+            }
+        }
+        if let Cow::Owned(path) = path {
+            log::debug!(
+                "[{}] Rewriting {:?} to {path:?}",
+                RequestId::get(&req).map_or("-", RequestId::as_str),
+                req.uri().path()
+            );
+            set_path(&mut req, &path)?;
+        }
+
+        let host = request_host(&req);
+        let mut found: Option<(Score, &Route, _, _)> = None;
+        let mut path_matched = false;
+        let mut allowed = Vec::new();
+        for (route, service) in &self.routes {
+            if !route.match_host(host) {
+                continue;
+            }
+            let params = match route.match_path(req.uri().path()) {
+                Some(params) => params,
+                None => continue,
+            };
+            path_matched = true;
+            // Less specific route accepting the method is preferred over 405.
+            if !route.match_method(req.method()) {
+                allowed.extend(route.methods.iter().cloned());
+                continue;
+            }
+            let score = route.score();
+            if found.as_ref().is_none_or(|(best, ..)| score > *best) {
+                found = Some((score, route, service, params));
+            }
+        }
+
+        match found {
+            Some((_, route, service, params)) => {
+                if !params.is_empty() {
+                    let path_params = req.extensions_mut().get_or_insert_default::<PathParams>();
+                    path_params.params.extend(params);
+                }
+                if route.strip {
+                    let rest: Vec<&str> = split_path(req.uri().path())
+                        .skip(route.segments.len())
+                        .collect();
+                    let path = format!("/{}", rest.join("/"));
+                    set_path(&mut req, &path)?;
+                }
+                service.call(req).await
+            }
+            None if path_matched => method_not_allowed(req.method(), allowed),
+            None => self.default.call(req).await,
+        }
+    }
+}
+
+fn method_not_allowed(
+    method: &Method,
+    mut allowed: Vec<Method>,
+) -> Result<Response<Outgoing>, Error> {
+    if allowed.contains(&Method::GET) {
+        allowed.push(Method::HEAD);
+    }
+    let mut names: Vec<&str> = allowed.iter().map(|m| m.as_str()).collect();
+    names.sort();
+    names.dedup();
+    let message = format!(
+        "Method {method} is not allowed, use one of: {}",
+        names.join(", ")
+    );
+    let mut res = ProxyError::new(ErrorKind::MethodNotAllowed, message).into_response();
+    res.headers_mut()
+        .insert(header::ALLOW, HeaderValue::from_str(&names.join(", "))?);
+    Ok(res)
+}
+
+#[test]
+fn route_matching() {
+    let route = Route::new("/v1/models/{id}");
+    assert_eq!(
+        route.match_path("/v1/models/gpt-4o"),
+        Some(vec![("id".into(), "gpt-4o".into())])
+    );
+    assert_eq!(route.match_path("/v1/models"), None);
+    assert_eq!(route.match_path("/v1/models/"), None);
+    assert_eq!(route.match_path("/v1/models/gpt-4o/x"), None);
+
+    let route = Route::prefix("/chat/completions");
+    assert!(route.match_path("/chat/completions").is_some());
+    assert!(route.match_path("/chat/completions/").is_some());
+    assert!(route.match_path("/chat/completions-foo").is_none());
+    assert!(Route::prefix("/").match_path("/anything").is_some());
+    assert!(Route::new("/").match_path("/").is_some());
+    assert!(Route::new("/").match_path("/index.html").is_none());
+
+    let route = Route::new("/").host("*.Example.com");
+    assert!(route.match_host(Some("api.example.COM")));
+    assert!(!route.match_host(Some("example.com")));
+    assert!(!route.match_host(None));
+
+    assert!(
+        Route::new("/")
+            .method(Method::GET)
+            .match_method(&Method::HEAD)
+    );
+    assert!(
+        !Route::new("/")
+            .method(Method::GET)
+            .match_method(&Method::POST)
+    );
+}
+
+#[tokio::test]
+async fn router() {
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Bytes;
+
+    use crate::{Nothing, server::test_request};
+
+    struct Text(&'static str);
+    impl Service for Text {
+        async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+            let id = req
+                .extensions()
+                .get::<PathParams>()
+                .and_then(|p| p.get("id"));
+            let text = format!("{} {}", self.0, id.unwrap_or_default());
+            Ok(Response::new(Full::from(text).map_err(Error::new).boxed()))
+        }
+    }
+
+    let router = Arc::new(
+        Router::new(Text("default"))
+            .push("/v1", Text("prefix"))
+            .route(Route::new("/v1/models").method(Method::GET), Text("list"))
+            .route(
+                Route::new("/v1/models/{id}").method(Method::GET),
+                Text("model"),
+            )
+            .route(
+                Route::new("/v1/models/{id}").method(Method::DELETE),
+                Text("delete"),
+            )
+            .route(Route::new("/v1/models/special"), Text("special"))
+            .route(Route::prefix("/").host("admin.local"), Nothing),
+    );
+    let request = async |method: Method, path: &str, host: &str| {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, host)
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = test_request(router.clone(), req).await;
+        (
+            res.status().as_u16(),
+            String::from_utf8(res.body().to_vec()).unwrap(),
+        )
+    };
+
+    use Method as M;
+    assert_eq!(request(M::GET, "/", "x").await, (200, "default ".into()));
+    assert_eq!(
+        request(M::GET, "/v1/chat", "x").await,
+        (200, "prefix ".into())
+    );
+    assert_eq!(request(M::GET, "/v1x", "x").await, (200, "default ".into()));
+    assert_eq!(
+        request(M::GET, "/v1/models", "x").await,
+        (200, "list ".into())
+    );
+    assert_eq!(
+        request(M::GET, "/v1/models/a", "x").await,
+        (200, "model a".into())
+    );
+    assert_eq!(
+        request(M::DELETE, "/v1/models/a", "x").await,
+        (200, "delete a".into())
+    );
+    assert_eq!(
+        request(M::PUT, "/v1/models/special", "x").await,
+        (200, "special ".into())
+    );
+    assert_eq!(
+        request(M::GET, "/v1/models/a/b", "x").await,
+        (200, "prefix ".into())
+    );
+    assert_eq!(request(M::GET, "/v1/models", "admin.local:80").await.0, 404);
+
+    assert_eq!(
+        request(M::POST, "/v1/models/a", "x").await,
+        (200, "prefix ".into())
+    );
+
+    let router = Arc::new(
+        Router::new(Text("default"))
+            .route(
+                Route::new("/v1/models/{id}").method(Method::GET),
+                Text("model"),
+            )
+            .route(
+                Route::new("/v1/models/{id}").method(Method::DELETE),
+                Text("delete"),
+            ),
+    );
+    let req = Request::post("/v1/models/a")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let res = test_request(router, req).await;
+    assert_eq!(res.status(), 405);
+    assert_eq!(res.headers()[header::ALLOW], "DELETE, GET, HEAD");
+}
+
+#[tokio::test]
+async fn mount_and_rewrite() {
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Bytes;
+
+    use crate::server::test_request;
+
+    struct Echo;
+    impl Service for Echo {
+        async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+            let original = req.extensions().get::<OriginalUri>().unwrap();
+            let text = format!("{} {}", req.uri(), original.0);
+            Ok(Response::new(Full::from(text).map_err(Error::new).boxed()))
+        }
+    }
+
+    let inner = Router::new(Echo).mount("/{version}/files", Echo);
+    let router = Arc::new(
+        Router::new(crate::Nothing)
+            .mount("/llm", inner)
+            .rewrite(Regex::new("^/api/(.*)$").unwrap(), "/llm/$1")
+            .rewrite(Regex::new("/old/").unwrap(), "/new/"),
+    );
+    let request = async |path: &str| {
+        let req = Request::get(path).body(Full::new(Bytes::new())).unwrap();
+        let res = test_request(router.clone(), req).await;
+        String::from_utf8(res.body().to_vec()).unwrap()
+    };
+
+    assert_eq!(
+        request("/llm/chat/completions?a=1").await,
+        "/chat/completions?a=1 /llm/chat/completions?a=1"
+    );
+    assert_eq!(request("/llm").await, "/ /llm");
+    assert_eq!(request("/llm/v1/files/a/b").await, "/a/b /llm/v1/files/a/b");
+    assert_eq!(request("/api/old/x").await, "/new/x /api/old/x");
+}
@@ -0,0 +1,798 @@
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Error;
+use http::Request;
+use hyper::{
+    body::{Body, Frame, Incoming, SizeHint},
+    service::service_fn,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use pin_project::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::Semaphore,
+    time::{Instant, sleep, sleep_until, timeout},
+};
+use tokio_util::task::TaskTracker;
+
+use crate::{
+    ProxyError, Service,
+    admin::{ActiveGuard, ListenerStats, Metrics},
+    listener::{Listener, PeerAddr, Stream},
+    proxy_protocol,
+    request_id::{RequestId, X_REQUEST_ID},
+    service::IntoService,
+    shutdown::Shutdown,
+    tls::TlsAcceptor,
+};
+
+/// Time given to aborted requests to send final messages to clients.
+const ABORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Delay before retrying after failed `accept`, doubled on each consecutive failure.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// HTTP server settings.
+#[derive(Clone)]
+pub struct Server {
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+    grace_period: Duration,
+    max_connections: Option<usize>,
+    header_read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    unix_mode: Option<u32>,
+    proxy_protocol: bool,
+    metrics: Metrics,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            tls: None,
+            shutdown: Shutdown::default(),
+            grace_period: Duration::from_secs(10),
+            max_connections: None,
+            header_read_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(60)),
+            unix_mode: None,
+            proxy_protocol: false,
+            metrics: Metrics::default(),
+        }
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle used to shut the server down.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Time given to in-flight requests to finish on shutdown before they are aborted.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Terminate TLS on incoming connections.
+    pub fn tls(mut self, tls: Option<TlsAcceptor>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Maximum number of concurrent client connections.
+    ///
+    /// New connections are not accepted until some of existing ones are closed.
+    pub fn max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Time given to client to complete TLS handshake and to send HTTP/1.1 request headers.
+    pub fn header_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    /// Connection is closed when there are no requests on it for this time.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Permission bits of Unix domain socket file, e.g. `0o660`.
+    pub fn unix_mode(mut self, mode: Option<u32>) -> Self {
+        self.unix_mode = mode;
+        self
+    }
+
+    /// Expect PROXY protocol v1 or v2 header at the beginning of each connection.
+    ///
+    /// Client address recovered from the header replaces the address of the proxy.
+    /// Connections without a valid header are rejected.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Metrics to register the listener in, may be shared between several servers.
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Bind to `addr` without serving connections yet.
+    ///
+    /// Address is either `host:port`, `unix:<path>` for Unix domain socket
+    /// or `systemd:<index-or-name>` for socket passed by systemd socket activation.
+    ///
+    /// Several servers sharing the same [`Shutdown`] may be bound first and then served concurrently,
+    /// each with its own service, so that the process fails early if any address is unavailable.
+    pub async fn bind(self, addr: impl AsRef<str>) -> Result<Bound, Error> {
+        // We create a listener and bind it to addr
+        let listener = Listener::bind(addr.as_ref(), self.unix_mode).await?;
+        self.bind_listener(listener)
+    }
+
+    /// Use already bound listener, e.g. `TcpListener` bound to port 0 in tests.
+    pub fn bind_listener(self, listener: impl Into<Listener>) -> Result<Bound, Error> {
+        let listener = listener.into();
+        let stats = self.metrics.listener(listener.local_addr()?);
+        Ok(Bound {
+            server: self,
+            listener,
+            stats,
+        })
+    }
+
+    /// Listen to `addr` and serve incoming connections using services created by `make_service`.
+    ///
+    /// Returns when shutdown is complete.
+    pub async fn serve<S, M, F>(self, addr: impl AsRef<str>, make_service: F) -> Result<(), Error>
+    where
+        S: IntoService<M>,
+        F: AsyncFnMut() -> Result<S, Error>,
+    {
+        self.bind(addr).await?.serve(make_service).await
+    }
+
+    async fn run<S, M, F>(
+        self,
+        listener: Listener,
+        stats: Arc<ListenerStats>,
+        mut make_service: F,
+    ) -> Result<(), Error>
+    where
+        S: IntoService<M>,
+        F: AsyncFnMut() -> Result<S, Error>,
+    {
+        log::info!(
+            "Listening for incoming {} connections at {}",
+            if self.tls.is_some() { "HTTPS" } else { "HTTP" },
+            listener.local_addr()?
+        );
+
+        if let Some(tls) = &self.tls {
+            tls.watch()?;
+        }
+
+        let connections = TaskTracker::new();
+        let limit = Arc::new(Semaphore::new(
+            self.max_connections.unwrap_or(Semaphore::MAX_PERMITS),
+        ));
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+
+        // We start a loop to continuously accept incoming connections
+        loop {
+            let permit = tokio::select! {
+                permit = limit.clone().acquire_owned() => permit?,
+                _ = self.shutdown.stopped() => break,
+            };
+            let (stream, addr) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(conn) => {
+                        backoff = MIN_ACCEPT_BACKOFF;
+                        conn
+                    }
+                    Err(err) => {
+                        // Errors like EMFILE are usually transient, so we wait a bit and try again.
+                        log::error!("Cannot accept incoming connection: {err}, retrying in {backoff:?}");
+                        tokio::select! {
+                            _ = sleep(backoff) => (),
+                            _ = self.shutdown.stopped() => break,
+                        }
+                        backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        continue;
+                    }
+                },
+                _ = self.shutdown.stopped() => break,
+            };
+            log::debug!("Incoming connection from {addr} established");
+            let counted = stats.connection();
+
+            let service = match make_service().await {
+                Ok(service) => Arc::new(service.into_service()),
+                Err(err) => {
+                    log::error!("Cannot create service for connection from {addr}: {err}");
+                    continue;
+                }
+            };
+
+            // Spawn a tokio task to serve multiple connections concurrently
+            let server = self.clone();
+            let stats = stats.clone();
+            connections.spawn(async move {
+                server
+                    .handle_connection(stream, addr, &stats, service)
+                    .await;
+                drop((counted, permit));
+            });
+        }
+
+        drop(listener);
+        connections.close();
+        log::info!(
+            "Waiting for {} connections to finish in-flight requests",
+            connections.len()
+        );
+        if timeout(self.grace_period, connections.wait())
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "Grace period expired, aborting {} connections",
+                connections.len()
+            );
+            self.shutdown.abort();
+            let _ = timeout(ABORT_TIMEOUT, connections.wait()).await;
+        }
+        log::info!("Server is shut down");
+        Ok(())
+    }
+
+    /// Run connection setup step limited by header read timeout.
+    async fn with_header_timeout<T>(
+        &self,
+        fut: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        match self.header_read_timeout {
+            Some(duration) => timeout(duration, fut)
+                .await
+                .unwrap_or_else(|_| Err(Error::msg("timed out"))),
+            None => fut.await,
+        }
+    }
+
+    async fn handle_connection<S: Service + 'static>(
+        &self,
+        mut stream: Stream,
+        mut addr: PeerAddr,
+        stats: &Arc<ListenerStats>,
+        service: Arc<S>,
+    ) {
+        if self.proxy_protocol {
+            let header = proxy_protocol::read_header(&mut stream);
+            match self.with_header_timeout(header).await {
+                Ok(Some(client)) => {
+                    log::debug!("Connection from {addr} is proxied for {client}");
+                    addr = PeerAddr::Tcp(client);
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    log::warn!("Cannot read PROXY protocol header from {addr}: {err}");
+                    return;
+                }
+            }
+        }
+
+        match &self.tls {
+            None => self.serve_connection(stream, addr, stats, service).await,
+            Some(tls) => {
+                let res = self.with_header_timeout(tls.accept(stream)).await;
+                match res {
+                    Ok(stream) => self.serve_connection(stream, addr, stats, service).await,
+                    Err(err) => log::warn!("TLS handshake with {addr} failed: {err}"),
+                }
+            }
+        }
+    }
+
+    async fn serve_connection<I, S>(
+        &self,
+        stream: I,
+        addr: PeerAddr,
+        stats: &Arc<ListenerStats>,
+        service: Arc<S>,
+    ) where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        S: Service + 'static,
+    {
+        // Use an adapter to access something implementing `tokio::io` traits as if they implement
+        // `hyper::rt` IO traits.
+        let io = TokioIo::new(stream);
+
+        let activity = Arc::new(Activity::new());
+
+        // Finally, we bind the incoming connection to our service.
+        // Protocol (HTTP/1.1 or HTTP/2) is detected automatically.
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(self.header_read_timeout);
+        let conn = builder.serve_connection(
+            io,
+            // `service_fn` converts our function in a `Service`
+            service_fn({
+                let shutdown = self.shutdown.clone();
+                let activity = activity.clone();
+                let stats = stats.clone();
+                move |mut req: Request<Incoming>| {
+                    let id = RequestId::from_headers(req.headers());
+                    log::debug!("[{id}] {} {} from {addr}", req.method(), req.uri());
+                    req.extensions_mut().insert(shutdown.clone());
+                    req.extensions_mut().insert(addr);
+                    req.extensions_mut().insert(id.clone());
+                    let guards = (activity.start(), stats.request());
+                    let service = service.clone();
+                    async move {
+                        let mut res = match service.call_arc(req).await {
+                            Ok(res) => res,
+                            Err(err) => {
+                                let err = ProxyError::from(err);
+                                if err.kind().status().is_server_error() {
+                                    log::error!("[{id}] Request failed: {err}");
+                                } else {
+                                    log::debug!("[{id}] Request rejected: {err}");
+                                }
+                                err.into_response()
+                            }
+                        };
+                        log::debug!("[{id}] Responded with {}", res.status());
+                        res.headers_mut()
+                            .insert(X_REQUEST_ID, id.header_value().clone());
+                        Ok::<_, Infallible>(res.map(|body| Guarded::new(body, guards)))
+                    }
+                }
+            }),
+        );
+        tokio::pin!(conn);
+
+        let mut closing = false;
+        let res = loop {
+            tokio::select! {
+                res = conn.as_mut() => break res,
+                _ = self.shutdown.stopped(), if !closing => {
+                    // Stop accepting new requests on this connection and wait for in-flight ones.
+                    conn.as_mut().graceful_shutdown();
+                    closing = true;
+                }
+                _ = activity.idle(self.idle_timeout), if !closing => {
+                    log::debug!("Incoming connection from {addr} is idle, closing");
+                    conn.as_mut().graceful_shutdown();
+                    closing = true;
+                }
+            }
+        };
+
+        if let Err(err) = res {
+            let incomplete = err
+                .downcast_ref::<hyper::Error>()
+                .is_some_and(|err| err.is_incomplete_message());
+            if incomplete {
+                log::warn!("Incoming connection from {addr} unexpected EOF");
+            } else {
+                log::error!("Incoming connection from {addr} failed: {err:?}");
+            }
+        } else {
+            log::debug!("Incoming connection closed: {addr}");
+        }
+    }
+}
+
+/// Server bound to an address, see [`Server::bind`].
+pub struct Bound {
+    server: Server,
+    listener: Listener,
+    stats: Arc<ListenerStats>,
+}
+
+impl Bound {
+    /// Address the server is bound to, useful when binding to port 0.
+    pub fn local_addr(&self) -> Result<String, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serve incoming connections using services created by `make_service`.
+    ///
+    /// Returns when shutdown is complete.
+    pub async fn serve<S, M, F>(self, make_service: F) -> Result<(), Error>
+    where
+        S: IntoService<M>,
+        F: AsyncFnMut() -> Result<S, Error>,
+    {
+        self.server
+            .run(self.listener, self.stats, make_service)
+            .await
+    }
+}
+
+/// Tracks requests on a single connection to detect when it becomes idle.
+struct Activity {
+    active: AtomicUsize,
+    last: Mutex<Instant>,
+}
+
+struct ActivityGuard {
+    activity: Arc<Activity>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn start(self: &Arc<Self>) -> ActivityGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ActivityGuard {
+            activity: self.clone(),
+        }
+    }
+
+    /// Resolves when there were no requests for `timeout`, never resolves if `timeout` is `None`.
+    async fn idle(&self, timeout: Option<Duration>) {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return std::future::pending().await,
+        };
+        loop {
+            let deadline = *self.last.lock().unwrap() + timeout;
+            let now = Instant::now();
+            if self.active.load(Ordering::SeqCst) == 0 && deadline <= now {
+                break;
+            }
+            sleep_until(deadline.max(now + timeout / 4)).await;
+        }
+    }
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        *self.activity.last.lock().unwrap() = Instant::now();
+        self.activity.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Response body that keeps the request counted as active until the body is finished or dropped.
+#[pin_project]
+struct Guarded<B> {
+    #[pin]
+    body: B,
+    guards: Option<(ActivityGuard, ActiveGuard)>,
+}
+
+impl<B> Guarded<B> {
+    fn new(body: B, guards: (ActivityGuard, ActiveGuard)) -> Self {
+        Self {
+            body,
+            guards: Some(guards),
+        }
+    }
+}
+
+impl<B: Body> Body for Guarded<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let poll = this.body.poll_frame(cx);
+        if let Poll::Ready(None | Some(Err(_))) = poll {
+            this.guards.take();
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+pub async fn serve<S, M, F>(addr: impl AsRef<str>, make_service: F) -> Result<(), Error>
+where
+    S: IntoService<M>,
+    F: AsyncFnMut() -> Result<S, Error>,
+{
+    Server::new().serve(addr, make_service).await
+}
+
+/// Serve connections from already bound `listener` with default settings.
+pub async fn serve_listener<S, M, F>(listener: TcpListener, make_service: F) -> Result<(), Error>
+where
+    S: IntoService<M>,
+    F: AsyncFnMut() -> Result<S, Error>,
+{
+    Server::new()
+        .bind_listener(listener)?
+        .serve(make_service)
+        .await
+}
+
+/// Serve `service` on a random port and send `req` to it.
+#[cfg(test)]
+pub(crate) async fn test_request<S: Service + 'static>(
+    service: Arc<S>,
+    req: hyper::Request<http_body_util::Full<hyper::body::Bytes>>,
+) -> hyper::Response<hyper::body::Bytes> {
+    use http_body_util::BodyExt;
+    use hyper::client::conn::http1;
+    use tokio::net::TcpStream;
+
+    let shutdown = Shutdown::new();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new()
+        .shutdown(shutdown.clone())
+        .grace_period(Duration::ZERO)
+        .bind_listener(listener)
+        .unwrap();
+    let server = tokio::spawn(server.serve(async move || Ok(service.clone())));
+
+    let stream = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+    let (mut sender, conn) = http1::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let (parts, body) = sender.send_request(req).await.unwrap().into_parts();
+    let body = body.collect().await.unwrap().to_bytes();
+
+    shutdown.stop();
+    server.await.unwrap().unwrap();
+    hyper::Response::from_parts(parts, body)
+}
+
+#[tokio::test]
+async fn idle_activity() {
+    let timeout = Some(Duration::from_millis(100));
+    let activity = Arc::new(Activity::new());
+
+    let guard = activity.start();
+    let start = Instant::now();
+    sleep(Duration::from_millis(300)).await;
+    drop(guard);
+    // Connection with long request isn't idle until request is finished
+    activity.idle(timeout).await;
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    let never = tokio::time::timeout(Duration::from_millis(100), activity.idle(None));
+    assert!(never.await.is_err());
+}
+
+#[tokio::test]
+async fn multiple_listeners() {
+    use crate::{Router, admin::Health, service::Nothing};
+    use http_body_util::Empty;
+    use hyper::{Request, body::Bytes, client::conn::http1};
+    use tokio::net::TcpStream;
+
+    async fn get(addr: &str, path: &str) -> u16 {
+        let stream = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+        let (mut sender, conn) = http1::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::get(path).body(Empty::<Bytes>::new()).unwrap();
+        sender.send_request(req).await.unwrap().status().as_u16()
+    }
+
+    let shutdown = Shutdown::new();
+    let metrics = Metrics::new();
+    let server = Server::new()
+        .shutdown(shutdown.clone())
+        .metrics(metrics.clone());
+    let public = server.clone().bind("127.0.0.1:0").await.unwrap();
+    let admin = server.bind("127.0.0.1:0").await.unwrap();
+    let (public_addr, admin_addr) = (public.local_addr().unwrap(), admin.local_addr().unwrap());
+    let servers = tokio::spawn(async move {
+        tokio::try_join!(
+            public.serve(async || Ok(Nothing)),
+            admin.serve(async || Ok(Router::new(Nothing).push("/health", Health))),
+        )
+    });
+
+    assert_eq!(get(&public_addr, "/health").await, 404);
+    assert_eq!(get(&admin_addr, "/health").await, 200);
+    assert!(metrics.render().contains(&format!(
+        "llm_proxy_requests_total{{listener=\"{admin_addr}\"}} 1\n"
+    )));
+
+    shutdown.stop();
+    servers.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn proxy_protocol_peer_addr() {
+    use http_body_util::{BodyExt, Full};
+    use hyper::{Request, Response, body::Incoming};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    struct EchoAddr;
+    impl Service for EchoAddr {
+        async fn call(&self, req: Request<Incoming>) -> Result<Response<crate::Outgoing>, Error> {
+            let addr = req.extensions().get::<PeerAddr>().unwrap().to_string();
+            Ok(Response::new(Full::from(addr).map_err(Error::new).boxed()))
+        }
+    }
+
+    let shutdown = Shutdown::new();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = Server::new()
+        .shutdown(shutdown.clone())
+        .proxy_protocol(true)
+        .bind_listener(listener)
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let server = tokio::spawn(server.serve(async || Ok(EchoAddr)));
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    stream
+        .write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 80\r\nGET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+    assert!(res.ends_with("\r\n\r\n192.0.2.1:56324"), "{res}");
+
+    // Connection without header is rejected
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut res = Vec::new();
+    // Server may close the connection with unread data, which results in reset
+    let _ = stream.read_to_end(&mut res).await;
+    assert!(res.is_empty());
+
+    shutdown.stop();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn h2c_prior_knowledge() {
+    use crate::service::Nothing;
+    use http_body_util::Empty;
+    use hyper::{
+        Version,
+        body::Bytes,
+        client::conn::{http1, http2},
+    };
+    use tokio::net::TcpStream;
+
+    let shutdown = Shutdown::new();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = Server::new()
+        .shutdown(shutdown.clone())
+        .bind_listener(listener)
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let server = tokio::spawn(server.serve(async || Ok(Nothing)));
+
+    let stream = TokioIo::new(TcpStream::connect(&addr).await.unwrap());
+    let (mut sender, conn) = http2::handshake(TokioExecutor::new(), stream)
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    let req = Request::get(format!("http://{addr}/"))
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = sender.send_request(req).await.unwrap();
+    assert_eq!(res.version(), Version::HTTP_2);
+    assert_eq!(res.status(), 404);
+
+    // HTTP/1.1 is still served on the same listener.
+    let stream = TokioIo::new(TcpStream::connect(&addr).await.unwrap());
+    let (mut sender, conn) = http1::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let req = Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+    let res = sender.send_request(req).await.unwrap();
+    assert_eq!(res.version(), Version::HTTP_11);
+
+    shutdown.stop();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn slow_stream_outlives_idle_timeout() {
+    use http_body_util::{BodyExt, Empty, StreamBody};
+    use hyper::{Request, Response, body::Bytes, client::conn::http1};
+    use tokio::net::TcpStream;
+    use tokio_stream::StreamExt;
+
+    struct Slow;
+    impl Service for Slow {
+        async fn call(&self, _req: Request<Incoming>) -> Result<Response<crate::Outgoing>, Error> {
+            let chunks = tokio_stream::iter(0..5)
+                .throttle(Duration::from_millis(60))
+                .map(|i| Ok(Frame::data(Bytes::from(i.to_string()))));
+            Ok(Response::new(StreamBody::new(chunks).boxed()))
+        }
+    }
+
+    let shutdown = Shutdown::new();
+    let metrics = Metrics::new();
+    let server = Server::new()
+        .shutdown(shutdown.clone())
+        .idle_timeout(Some(Duration::from_millis(100)))
+        .metrics(metrics.clone())
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let server = tokio::spawn(server.serve(async || Ok(Slow)));
+    let active = |n: usize| format!("llm_proxy_requests_active{{listener=\"{addr}\"}} {n}\n");
+
+    let stream = TokioIo::new(TcpStream::connect(&addr).await.unwrap());
+    let (mut sender, conn) = http1::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let req = Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+    let mut body = sender.send_request(req).await.unwrap().into_body();
+    let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+    assert!(metrics.render().contains(&active(1)));
+    let rest = body.collect().await.unwrap().to_bytes();
+    assert_eq!([first, rest].concat(), b"01234");
+    assert!(metrics.render().contains(&active(0)));
+
+    shutdown.stop();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn service_error() {
+    use http_body_util::Full;
+    use hyper::{Response, body::Bytes};
+
+    struct Failing;
+    impl Service for Failing {
+        async fn call(&self, _req: Request<Incoming>) -> Result<Response<crate::Outgoing>, Error> {
+            Err(anyhow::anyhow!("/etc/secret is missing"))
+        }
+    }
+
+    let req = Request::get("/")
+        .header(X_REQUEST_ID, "req-1")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let res = test_request(Arc::new(Failing), req).await;
+    assert_eq!(res.status(), 500);
+    assert_eq!(res.headers()[X_REQUEST_ID], "req-1");
+    assert_eq!(
+        res.body(),
+        r#"{"error":{"message":"Internal server error","type":"server_error","code":null}}"#
+    );
+}
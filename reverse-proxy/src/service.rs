@@ -1,13 +1,22 @@
-use std::{convert::Infallible, pin::Pin, sync::Arc};
+use std::{pin::Pin, sync::Arc};
 
 use anyhow::Error;
 use http::{Request, Response};
-use http_body_util::{BodyExt, Empty, combinators::BoxBody};
+use http_body_util::combinators::BoxBody;
 use hyper::body::{Bytes, Incoming};
+use tower::Layer;
+
+use crate::{
+    error::ProxyError,
+    tower_compat::{FromTower, IntoTower},
+};
 
 pub type Outgoing = BoxBody<Bytes, Error>;
 
-/// TODO: Use tower::Service or hyper::Service
+/// HTTP service.
+///
+/// Unlike [`tower::Service`] it doesn't need `&mut self` and readiness checks, so it can be shared
+/// between connections. Use [`Service::into_tower`] and [`FromTower`] to convert between the two.
 pub trait Service: Send + Sync {
     fn call(
         &self,
@@ -30,6 +39,43 @@ pub trait Service: Send + Sync {
     {
         Arc::new(self)
     }
+
+    /// Convert into [`tower::Service`] to wrap it with tower middleware.
+    fn into_tower(self) -> IntoTower<Self>
+    where
+        Self: Sized,
+    {
+        IntoTower::new(Arc::new(self))
+    }
+
+    /// Wrap with [`tower::Layer`] keeping the result usable as [`Service`].
+    fn layer<L>(self, layer: L) -> FromTower<L::Service>
+    where
+        Self: Sized,
+        L: Layer<IntoTower<Self>>,
+    {
+        FromTower::new(layer.layer(self.into_tower()))
+    }
+}
+
+/// Conversion into [`Service`], implemented both for our services and for [`tower::Service`]s.
+///
+/// `M` is a marker type distinguishing the implementations.
+pub trait IntoService<M> {
+    type Service: Service + 'static;
+
+    fn into_service(self) -> Self::Service;
+}
+
+/// Marker of [`IntoService`] implementation for [`Service`]s.
+pub enum Native {}
+
+impl<S: Service + 'static> IntoService<Native> for S {
+    type Service = S;
+
+    fn into_service(self) -> S {
+        self
+    }
 }
 
 pub trait ServiceDyn: Send + Sync {
@@ -57,53 +103,172 @@ impl Service for dyn ServiceDyn {
     }
 }
 
-pub struct Router {
-    routes: Vec<(String, Arc<dyn ServiceDyn>)>,
-    default: Arc<dyn ServiceDyn>,
+#[derive(Clone, Default, Debug)]
+pub struct Nothing;
+
+impl Service for Nothing {
+    async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+        let message = format!("Nothing found at {:?}", req.uri().path());
+        Ok(ProxyError::not_found(message).into_response())
+    }
 }
 
-impl Router {
-    pub fn new<S: Service + 'static>(default: S) -> Self {
-        Self {
-            routes: Vec::new(),
-            default: Arc::new(default),
+impl<S: Service> Service for Option<S> {
+    async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+        match self {
+            Some(service) => service.call(req).await,
+            None => Nothing.call(req).await,
         }
     }
-    pub fn push<S: Service + 'static>(mut self, prefix: &str, service: S) -> Self {
-        self.routes.push((prefix.to_string(), Arc::new(service)));
-        self
+}
+
+impl<S: Service + ?Sized> Service for Arc<S> {
+    fn call(
+        &self,
+        req: Request<Incoming>,
+    ) -> impl Future<Output = Result<Response<Outgoing>, Error>> + Send + '_ {
+        (**self).call(req)
     }
 }
 
-impl Service for Router {
-    async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
-        for (prefix, service) in &self.routes {
-            if req.uri().path().starts_with(prefix) {
-                return service.call(req).await;
-            }
-        }
-        self.default.call(req).await
+/// Result of [`Middleware::before`].
+pub enum Flow {
+    /// Pass request to the inner service.
+    Continue(Request<Incoming>),
+    /// Respond without calling the inner service.
+    Respond(Response<Outgoing>),
+}
+
+/// Rest of the service chain passed to [`Middleware::call`].
+pub struct Next<'a> {
+    service: &'a (dyn ServiceDyn + 'a),
+}
+
+impl Next<'_> {
+    pub async fn run(self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+        self.service.call_dyn(req).await
     }
 }
 
-#[derive(Clone, Default, Debug)]
-pub struct Nothing;
+/// Wraps a [`Service`] to pre-process requests, post-process responses or respond early.
+///
+/// Simple middleware implements [`before`](Self::before) and/or [`after`](Self::after) hooks,
+/// while [`call`](Self::call) may be overridden to control the whole request.
+pub trait Middleware: Send + Sync {
+    fn before(
+        &self,
+        req: Request<Incoming>,
+    ) -> impl Future<Output = Result<Flow, Error>> + Send + '_ {
+        async move { Ok(Flow::Continue(req)) }
+    }
 
-impl Service for Nothing {
-    async fn call(&self, _req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
-        Ok(Response::builder().status(404).body(
-            Empty::new()
-                .map_err(|_: Infallible| -> Error { unreachable!() })
-                .boxed(),
-        )?)
+    fn after(
+        &self,
+        res: Response<Outgoing>,
+    ) -> impl Future<Output = Result<Response<Outgoing>, Error>> + Send + '_ {
+        async move { Ok(res) }
+    }
+
+    fn call<'a>(
+        &'a self,
+        req: Request<Incoming>,
+        next: Next<'a>,
+    ) -> impl Future<Output = Result<Response<Outgoing>, Error>> + Send + 'a {
+        async move {
+            let req = match self.before(req).await? {
+                Flow::Continue(req) => req,
+                Flow::Respond(res) => return Ok(res),
+            };
+            let res = next.run(req).await?;
+            self.after(res).await
+        }
     }
 }
 
-impl<S: Service> Service for Option<S> {
+/// Service wrapped with middleware, see [`ServiceExt::with`].
+#[derive(Clone, Debug)]
+pub struct Layered<S, M> {
+    service: S,
+    middleware: M,
+}
+
+impl<S: Service, M: Middleware> Service for Layered<S, M> {
     async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
-        match self {
-            Some(service) => service.call(req).await,
-            None => Nothing.call(req).await,
+        let next = Next {
+            service: &self.service,
+        };
+        self.middleware.call(req, next).await
+    }
+}
+
+pub trait ServiceExt: Service + Sized {
+    /// Wrap the service with `middleware`.
+    ///
+    /// Middleware added last is called first.
+    fn with<M: Middleware>(self, middleware: M) -> Layered<Self, M> {
+        Layered {
+            service: self,
+            middleware,
+        }
+    }
+}
+
+impl<S: Service> ServiceExt for S {}
+
+#[tokio::test]
+async fn middleware() {
+    use http::{HeaderValue, header};
+    use http_body_util::{BodyExt, Full};
+
+    use crate::server::test_request;
+
+    struct Hello;
+    impl Service for Hello {
+        async fn call(&self, _req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+            Ok(Response::new(
+                Full::from("hello").map_err(Error::new).boxed(),
+            ))
+        }
+    }
+
+    struct Auth;
+    impl Middleware for Auth {
+        async fn before(&self, req: Request<Incoming>) -> Result<Flow, Error> {
+            if req.headers().get(header::AUTHORIZATION).is_some() {
+                Ok(Flow::Continue(req))
+            } else {
+                Ok(Flow::Respond(Nothing.call(req).await?))
+            }
+        }
+    }
+
+    struct Header(&'static str);
+    impl Middleware for Header {
+        async fn after(&self, mut res: Response<Outgoing>) -> Result<Response<Outgoing>, Error> {
+            res.headers_mut()
+                .append("x-chain", HeaderValue::from_static(self.0));
+            Ok(res)
         }
     }
+
+    let service = Arc::new(Hello.with(Header("inner")).with(Auth).with(Header("outer")));
+    let request = |auth: bool| {
+        let mut req = Request::get("/");
+        if auth {
+            req = req.header(header::AUTHORIZATION, "Bearer key");
+        }
+        req.body(Full::new(Bytes::new())).unwrap()
+    };
+
+    let res = test_request(service.clone(), request(true)).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body(), "hello");
+    let chain: Vec<_> = res.headers().get_all("x-chain").iter().collect();
+    assert_eq!(chain, ["inner", "outer"]);
+
+    // Short-circuit skips the inner service and middleware, but not the outer one
+    let res = test_request(service, request(false)).await;
+    assert_eq!(res.status(), 404);
+    let chain: Vec<_> = res.headers().get_all("x-chain").iter().collect();
+    assert_eq!(chain, ["outer"]);
 }
@@ -0,0 +1,212 @@
+use std::io;
+
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+/// Handle used to gracefully shut the server down.
+///
+/// Shutdown happens in two stages:
+/// + *stop* - new connections are not accepted anymore, in-flight requests are allowed to finish,
+/// + *abort* - requests that didn't finish in time are terminated.
+///
+/// Server adds the handle to extensions of every incoming request.
+#[derive(Clone, Default, Debug)]
+pub struct Shutdown {
+    stop: CancellationToken,
+    abort: CancellationToken,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop accepting new connections and wait for in-flight requests.
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    /// Terminate in-flight requests.
+    pub fn abort(&self) {
+        self.stop.cancel();
+        self.abort.cancel();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.abort.is_cancelled()
+    }
+
+    /// Resolves when shutdown is started.
+    pub fn stopped(&self) -> WaitForCancellationFutureOwned {
+        self.stop.clone().cancelled_owned()
+    }
+
+    /// Resolves when in-flight requests must be terminated.
+    pub fn aborted(&self) -> WaitForCancellationFutureOwned {
+        self.abort.clone().cancelled_owned()
+    }
+
+    /// Start shutdown on SIGTERM or SIGINT.
+    pub fn stop_on_signal(&self) -> Result<(), io::Error> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        self.stop_when(async move {
+            tokio::select! {
+                _ = terminate.recv() => log::info!("SIGTERM received, shutting down"),
+                _ = interrupt.recv() => log::info!("SIGINT received, shutting down"),
+            }
+        });
+        Ok(())
+    }
+
+    /// Start shutdown when `signal` resolves.
+    fn stop_when(&self, signal: impl Future<Output = ()> + Send + 'static) {
+        let this = self.clone();
+        tokio::task::spawn(async move {
+            tokio::select! {
+                _ = signal => {}
+                _ = this.stopped() => return,
+            }
+            this.stop();
+        });
+    }
+}
+
+/// Proxy streaming responses from an upstream whose events are sent through the returned channel.
+///
+/// Returns the proxy address, the proxy server task and the channel.
+#[cfg(test)]
+async fn test_streaming_proxy(
+    shutdown: &Shutdown,
+    grace_period: std::time::Duration,
+) -> (
+    String,
+    tokio::task::JoinHandle<Result<(), anyhow::Error>>,
+    tokio::sync::mpsc::Receiver<tokio::sync::mpsc::Sender<&'static str>>,
+) {
+    use anyhow::Error;
+    use http::{Request, Response};
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::body::{Bytes, Frame, Incoming};
+    use tokio::sync::mpsc;
+    use tokio_stream::{StreamExt, wrappers::ReceiverStream};
+
+    use crate::{Outgoing, Server, Service, openai::proxy::ReverseProxy};
+
+    /// Upstream handing over a sender of response events for each request.
+    struct Upstream(mpsc::Sender<mpsc::Sender<&'static str>>);
+    impl Service for Upstream {
+        async fn call(&self, _req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+            let (send, recv) = mpsc::channel(1);
+            self.0.send(send).await?;
+            let events = ReceiverStream::new(recv)
+                .map(|data| Ok(Frame::data(Bytes::from(format!("data: {data}\n\n")))));
+            let res = Response::builder()
+                .header(http::header::CONTENT_TYPE, "text/event-stream")
+                .body(StreamBody::new(events).boxed())?;
+            Ok(res)
+        }
+    }
+
+    let (requests, events) = mpsc::channel(1);
+    let upstream = Server::new().bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", upstream.local_addr().unwrap());
+    tokio::spawn(upstream.serve(async move || Ok(Upstream(requests.clone()))));
+
+    let proxy = ReverseProxy::new(url.parse().unwrap());
+    let server = Server::new()
+        .shutdown(shutdown.clone())
+        .grace_period(grace_period)
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let server = tokio::spawn(server.serve(async move || Ok(proxy.clone())));
+    (addr, server, events)
+}
+
+/// Start streaming chat completion and return the response body.
+#[cfg(test)]
+async fn test_stream(addr: &str) -> hyper::body::Incoming {
+    use http_body_util::Full;
+    use hyper::{Request, body::Bytes, client::conn::http1};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpStream;
+
+    let stream = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+    let (mut sender, conn) = http1::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let body = r#"{"model": "m", "messages": [{"role": "user", "content": "hi"}], "stream": true}"#;
+    let req = Request::post("/")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap();
+    let res = sender.send_request(req).await.unwrap();
+    assert_eq!(res.status(), 200);
+    res.into_body()
+}
+
+#[cfg(test)]
+const TEST_CHUNK: &str = r#"{"choices": [{"index": 0, "delta": {"content": "a"}}]}"#;
+
+#[tokio::test]
+async fn drain_on_signal() {
+    use std::time::Duration;
+
+    use http_body_util::BodyExt;
+
+    let shutdown = Shutdown::new();
+    let (signal, received) = tokio::sync::oneshot::channel();
+    shutdown.stop_when(async move {
+        received.await.ok();
+    });
+    let (addr, server, mut events) = test_streaming_proxy(&shutdown, Duration::from_secs(10)).await;
+    let mut body = test_stream(&addr).await;
+    let upstream = events.recv().await.unwrap();
+    upstream.send(TEST_CHUNK).await.unwrap();
+    let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+    assert!(first.starts_with(b"data: "));
+
+    signal.send(()).unwrap();
+    shutdown.stopped().await;
+
+    // In-flight stream is finished normally.
+    upstream.send(TEST_CHUNK).await.unwrap();
+    upstream.send("[DONE]").await.unwrap();
+    drop(upstream);
+    let rest = body.collect().await.unwrap().to_bytes();
+    let rest = String::from_utf8_lossy(&rest);
+    assert!(rest.contains(r#""content":"a""#), "{rest}");
+    assert!(rest.ends_with("data: [DONE]\n\n"), "{rest}");
+    assert!(!rest.contains("error"), "{rest}");
+    server.await.unwrap().unwrap();
+    assert!(!shutdown.is_aborted());
+}
+
+#[tokio::test]
+async fn abort_after_grace_period() {
+    use std::time::Duration;
+
+    use http_body_util::BodyExt;
+
+    let shutdown = Shutdown::new();
+    let (addr, server, mut events) =
+        test_streaming_proxy(&shutdown, Duration::from_millis(100)).await;
+    let mut body = test_stream(&addr).await;
+    let upstream = events.recv().await.unwrap();
+    upstream.send(TEST_CHUNK).await.unwrap();
+    let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+    assert!(first.starts_with(b"data: "));
+
+    // Upstream never finishes the stream.
+    shutdown.stop();
+    let data = body.collect().await.unwrap().to_bytes();
+    let data = String::from_utf8_lossy(&data);
+    assert!(data.contains(r#""code":"shutdown""#), "{data}");
+    assert!(data.ends_with("data: [DONE]\n\n"), "{data}");
+    server.await.unwrap().unwrap();
+    assert!(shutdown.is_aborted());
+}
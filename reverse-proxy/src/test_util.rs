@@ -0,0 +1,39 @@
+//! Helpers shared by tests.
+
+use std::path::{Path, PathBuf};
+
+/// Temporary directory that is removed when dropped, even if the test panics.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let mut suffix = [0; 8];
+        openssl::rand::rand_bytes(&mut suffix).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "llm-reverse-proxy-{name}-{:016x}",
+            u64::from_le_bytes(suffix)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Write file relative to the directory, creating parent directories.
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
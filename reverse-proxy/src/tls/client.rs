@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Error, bail};
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+
+/// TLS settings for connections to the upstream server.
+///
+/// Peer certificate and hostname are verified against system CA certificates by default.
+#[derive(Clone, Default, Debug)]
+pub struct ClientTlsConfig {
+    ca_file: Option<PathBuf>,
+    ca_dir: Option<PathBuf>,
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    insecure: bool,
+}
+
+impl ClientTlsConfig {
+    /// PEM file with additional trusted CA certificates.
+    pub fn ca_file(mut self, path: Option<impl AsRef<Path>>) -> Self {
+        self.ca_file = path.map(|p| p.as_ref().to_owned());
+        self
+    }
+
+    /// Directory with additional trusted CA certificates in OpenSSL hashed format (see `openssl rehash`).
+    pub fn ca_dir(mut self, path: Option<impl AsRef<Path>>) -> Self {
+        self.ca_dir = path.map(|p| p.as_ref().to_owned());
+        self
+    }
+
+    /// Client certificate chain and private key PEM files used for mutual TLS.
+    pub fn client_cert(
+        mut self,
+        cert_and_key: Option<(impl AsRef<Path>, impl AsRef<Path>)>,
+    ) -> Self {
+        (self.cert_file, self.key_file) = match cert_and_key {
+            Some((cert, key)) => (
+                Some(cert.as_ref().to_owned()),
+                Some(key.as_ref().to_owned()),
+            ),
+            None => (None, None),
+        };
+        self
+    }
+
+    /// Disable certificate and hostname verification.
+    ///
+    /// **Never use this in production**, it makes connection vulnerable to MITM attacks.
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        if insecure {
+            log::warn!(
+                "!!! TLS certificate verification for upstream connections is DISABLED, \
+                 connections are vulnerable to MITM attacks !!!"
+            );
+        }
+        self.insecure = insecure;
+        self
+    }
+
+    pub fn is_insecure(&self) -> bool {
+        self.insecure
+    }
+
+    /// Create OpenSSL connector, `alpn` is a list of protocols in wire format.
+    pub fn build(&self, alpn: Option<&[u8]>) -> Result<SslConnector, Error> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+
+        if self.insecure {
+            builder.set_verify(SslVerifyMode::NONE);
+        } else {
+            builder.set_verify(SslVerifyMode::PEER);
+        }
+
+        if self.ca_file.is_some() || self.ca_dir.is_some() {
+            builder.load_verify_locations(self.ca_file.as_deref(), self.ca_dir.as_deref())?;
+        }
+
+        match (&self.cert_file, &self.key_file) {
+            (Some(cert), Some(key)) => {
+                builder.set_certificate_chain_file(cert)?;
+                builder.set_private_key_file(key, SslFiletype::PEM)?;
+                builder.check_private_key()?;
+            }
+            (None, None) => (),
+            _ => bail!("Both client certificate and key must be set"),
+        }
+
+        if let Some(protos) = alpn {
+            builder.set_alpn_protos(protos)?;
+        }
+
+        Ok(builder.build())
+    }
+}
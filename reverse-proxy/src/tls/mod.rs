@@ -0,0 +1,108 @@
+pub mod client;
+pub mod server;
+
+pub use self::{
+    client::ClientTlsConfig,
+    server::{ServerTlsConfig, TlsAcceptor},
+};
+
+/// Certificates signed by a locally generated CA for tests.
+#[cfg(test)]
+pub(crate) mod test_certs {
+    use std::path::PathBuf;
+
+    use crate::test_util::TempDir;
+
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        x509::{
+            X509, X509NameBuilder,
+            extension::{BasicConstraints, SubjectAlternativeName},
+        },
+    };
+
+    pub struct Cert {
+        pub cert: X509,
+        pub key: PKey<Private>,
+        /// Holds PEM files of the certificate while it is alive.
+        dir: TempDir,
+    }
+
+    fn build(name: &str, sans: &[&str], issuer: Option<&Cert>) -> Cert {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(rand_serial()).unwrap().to_asn1_integer();
+        builder.set_serial_number(&serial.unwrap()).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some(issuer) => {
+                builder.set_issuer_name(issuer.cert.subject_name()).unwrap();
+                let mut san = SubjectAlternativeName::new();
+                for name in sans {
+                    match name.parse::<std::net::IpAddr>() {
+                        Ok(_) => san.ip(name),
+                        Err(_) => san.dns(name),
+                    };
+                }
+                let san = san.build(&builder.x509v3_context(Some(&issuer.cert), None));
+                builder.append_extension(san.unwrap()).unwrap();
+                builder.sign(&issuer.key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&subject).unwrap();
+                let constraints = BasicConstraints::new().critical().ca().build();
+                builder.append_extension(constraints.unwrap()).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+
+        let cert = builder.build();
+        let dir = TempDir::new("cert");
+        dir.write("cert.pem", cert.to_pem().unwrap());
+        dir.write("key.pem", key.private_key_to_pem_pkcs8().unwrap());
+        Cert { cert, key, dir }
+    }
+
+    fn rand_serial() -> u32 {
+        let mut bytes = [0; 4];
+        openssl::rand::rand_bytes(&mut bytes).unwrap();
+        u32::from_le_bytes(bytes) >> 1
+    }
+
+    pub fn ca(name: &str) -> Cert {
+        build(name, &[], None)
+    }
+
+    pub fn signed(ca: &Cert, name: &str, sans: &[&str]) -> Cert {
+        build(name, sans, Some(ca))
+    }
+
+    impl Cert {
+        pub fn cert_file(&self) -> PathBuf {
+            self.dir.path().join("cert.pem")
+        }
+
+        pub fn key_file(&self) -> PathBuf {
+            self.dir.path().join("key.pem")
+        }
+    }
+}
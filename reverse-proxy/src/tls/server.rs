@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Error;
+use openssl::ssl::{
+    AlpnError, NameType, SniError, Ssl, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype,
+    SslMethod, select_next_proto,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    signal::unix::{SignalKind, signal},
+};
+use tokio_openssl::SslStream;
+
+#[derive(Clone, Debug)]
+struct CertPaths {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+}
+
+impl CertPaths {
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_file)?, modified(&self.key_file)?))
+    }
+}
+
+/// TLS settings for incoming connections.
+#[derive(Clone, Debug)]
+pub struct ServerTlsConfig {
+    default: CertPaths,
+    sni: Vec<(String, CertPaths)>,
+    reload_interval: Option<Duration>,
+}
+
+impl ServerTlsConfig {
+    /// Certificate chain and private key PEM files used by default.
+    pub fn new(cert_file: impl AsRef<Path>, key_file: impl AsRef<Path>) -> Self {
+        Self {
+            default: CertPaths {
+                cert_file: cert_file.as_ref().to_owned(),
+                key_file: key_file.as_ref().to_owned(),
+            },
+            sni: Vec::new(),
+            reload_interval: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// Certificate used when client requests `hostname` via SNI.
+    ///
+    /// Hostname may be a wildcard like `*.example.com`.
+    pub fn sni(
+        mut self,
+        hostname: impl Into<String>,
+        cert_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>,
+    ) -> Self {
+        self.sni.push((
+            hostname.into().to_ascii_lowercase(),
+            CertPaths {
+                cert_file: cert_file.as_ref().to_owned(),
+                key_file: key_file.as_ref().to_owned(),
+            },
+        ));
+        self
+    }
+
+    /// How often certificate files are checked for changes, `None` disables checking.
+    pub fn reload_interval(mut self, interval: Option<Duration>) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    pub fn build(self) -> Result<TlsAcceptor, Error> {
+        let contexts = Contexts::load(&self)?;
+        Ok(TlsAcceptor {
+            inner: Arc::new(AcceptorInner {
+                config: self,
+                contexts: RwLock::new(Arc::new(contexts)),
+                watching: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &CertPaths> {
+        [&self.default]
+            .into_iter()
+            .chain(self.sni.iter().map(|(_, paths)| paths))
+    }
+}
+
+/// SSL contexts built from certificate files at some moment.
+struct Contexts {
+    default: SslContext,
+    modified: Vec<Option<(SystemTime, SystemTime)>>,
+}
+
+impl Contexts {
+    fn load(config: &ServerTlsConfig) -> Result<Self, Error> {
+        // Collect modification times before reading files to not miss changes made during reading.
+        let modified = config.paths().map(|paths| paths.modified()).collect();
+
+        let mut by_name = HashMap::new();
+        for (hostname, paths) in &config.sni {
+            by_name.insert(
+                hostname.clone(),
+                context_builder(paths)?.build().into_context(),
+            );
+        }
+
+        let mut default = context_builder(&config.default)?;
+        if !by_name.is_empty() {
+            default.set_servername_callback(move |ssl, _| {
+                let hostname = match ssl.servername(NameType::HOST_NAME) {
+                    Some(name) => name.to_ascii_lowercase(),
+                    None => return Ok(()),
+                };
+                let context = by_name.get(&hostname).or_else(|| {
+                    let (_, parent) = hostname.split_once('.')?;
+                    by_name.get(&format!("*.{parent}"))
+                });
+                if let Some(context) = context {
+                    ssl.set_ssl_context(context)
+                        .map_err(|_| SniError::ALERT_FATAL)?;
+                }
+                Ok(())
+            });
+        }
+
+        Ok(Self {
+            default: default.build().into_context(),
+            modified,
+        })
+    }
+}
+
+/// ALPN protocols supported by server in wire format, in order of preference.
+const ALPN_H2_HTTP1: &[u8] = b"\x02h2\x08http/1.1";
+
+fn context_builder(paths: &CertPaths) -> Result<SslAcceptorBuilder, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_certificate_chain_file(&paths.cert_file)?;
+    builder.set_private_key_file(&paths.key_file, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_alpn_select_callback(|_, client| {
+        // Clients that don't use ALPN will use HTTP/1.1
+        select_next_proto(ALPN_H2_HTTP1, client).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder)
+}
+
+struct AcceptorInner {
+    config: ServerTlsConfig,
+    contexts: RwLock<Arc<Contexts>>,
+    watching: AtomicBool,
+}
+
+/// Performs TLS handshake on incoming connections.
+///
+/// Cloning is cheap, clones share the same certificates.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: Arc<AcceptorInner>,
+}
+
+impl TlsAcceptor {
+    pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> Result<SslStream<S>, Error> {
+        let contexts = self.inner.contexts.read().unwrap().clone();
+        let ssl = Ssl::new(&contexts.default)?;
+        let mut stream = SslStream::new(ssl, stream)?;
+        Pin::new(&mut stream).accept().await?;
+        Ok(stream)
+    }
+
+    /// Reload certificates from files.
+    ///
+    /// Previous certificates are kept if loading fails.
+    pub fn reload(&self) -> Result<(), Error> {
+        let contexts = Contexts::load(&self.inner.config)?;
+        *self.inner.contexts.write().unwrap() = Arc::new(contexts);
+        log::info!("TLS certificates reloaded");
+        Ok(())
+    }
+
+    fn is_changed(&self) -> bool {
+        let contexts = self.inner.contexts.read().unwrap().clone();
+        self.inner
+            .config
+            .paths()
+            .map(|paths| paths.modified())
+            .ne(contexts.modified.iter().cloned())
+    }
+
+    /// Reload certificates when files change or on SIGHUP until acceptor is dropped.
+    ///
+    /// Does nothing if the acceptor is already being watched.
+    pub fn watch(&self) -> Result<(), Error> {
+        if self.inner.watching.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut hangup = signal(SignalKind::hangup())?;
+        let interval = self.inner.config.reload_interval;
+        let acceptor = Arc::downgrade(&self.inner);
+        tokio::task::spawn(async move {
+            let mut interval = interval.map(tokio::time::interval);
+            loop {
+                let reload_anyway = tokio::select! {
+                    _ = async {
+                        match &mut interval {
+                            Some(interval) => interval.tick().await,
+                            None => std::future::pending().await,
+                        }
+                    } => false,
+                    _ = hangup.recv() => {
+                        log::info!("SIGHUP received");
+                        true
+                    },
+                };
+                let acceptor = match acceptor.upgrade() {
+                    Some(inner) => TlsAcceptor { inner },
+                    None => break,
+                };
+                if !reload_anyway && !acceptor.is_changed() {
+                    continue;
+                }
+                if let Err(err) = acceptor.reload() {
+                    log::error!("Cannot reload TLS certificates: {err}");
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Perform handshake with `acceptor` offering HTTP/2 and return the common name of the server certificate.
+#[cfg(test)]
+async fn test_handshake(
+    acceptor: &TlsAcceptor,
+    ca: &super::test_certs::Cert,
+    hostname: &str,
+) -> String {
+    use openssl::{nid::Nid, ssl::SslConnector};
+
+    let (client, server) = tokio::io::duplex(4096);
+    let server = tokio::task::spawn({
+        let acceptor = acceptor.clone();
+        async move { acceptor.accept(server).await.map(|_| ()) }
+    });
+
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector
+        .cert_store_mut()
+        .add_cert(ca.cert.clone())
+        .unwrap();
+    connector.set_alpn_protos(ALPN_H2_HTTP1).unwrap();
+    let ssl = connector
+        .build()
+        .configure()
+        .unwrap()
+        .into_ssl(hostname)
+        .unwrap();
+    let mut client = SslStream::new(ssl, client).unwrap();
+    Pin::new(&mut client).connect().await.unwrap();
+    server.await.unwrap().unwrap();
+    assert_eq!(client.ssl().selected_alpn_protocol(), Some(&b"h2"[..]));
+
+    let cert = client.ssl().peer_certificate().unwrap();
+    let name = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next();
+    name.unwrap().data().as_utf8().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn sni() {
+    use super::test_certs;
+
+    let ca = test_certs::ca("Test CA");
+    let default = test_certs::signed(&ca, "default", &["localhost"]);
+    let exact = test_certs::signed(&ca, "exact", &["example.com"]);
+    let wildcard = test_certs::signed(&ca, "wildcard", &["*.example.com"]);
+    let acceptor = ServerTlsConfig::new(default.cert_file(), default.key_file())
+        .sni("Example.com", exact.cert_file(), exact.key_file())
+        .sni("*.example.com", wildcard.cert_file(), wildcard.key_file())
+        .build()
+        .unwrap();
+
+    assert_eq!(test_handshake(&acceptor, &ca, "localhost").await, "default");
+    assert_eq!(test_handshake(&acceptor, &ca, "example.com").await, "exact");
+    assert_eq!(
+        test_handshake(&acceptor, &ca, "www.example.com").await,
+        "wildcard"
+    );
+}
+
+#[tokio::test]
+async fn reload() {
+    use super::test_certs;
+
+    let ca = test_certs::ca("Test CA");
+    let old = test_certs::signed(&ca, "old", &["localhost"]);
+    let (cert_file, key_file) = (old.cert_file(), old.key_file());
+    let acceptor = ServerTlsConfig::new(&cert_file, &key_file).build().unwrap();
+    assert!(!acceptor.is_changed());
+    assert_eq!(test_handshake(&acceptor, &ca, "localhost").await, "old");
+
+    let new = test_certs::signed(&ca, "new", &["localhost"]);
+    std::fs::write(&cert_file, new.cert.to_pem().unwrap()).unwrap();
+    std::fs::write(&key_file, new.key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    // Broken files must not replace working certificates.
+    std::fs::write(&key_file, b"").unwrap();
+    assert!(acceptor.reload().is_err());
+    assert_eq!(test_handshake(&acceptor, &ca, "localhost").await, "old");
+
+    std::fs::write(&key_file, new.key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    acceptor.reload().unwrap();
+    assert_eq!(test_handshake(&acceptor, &ca, "localhost").await, "new");
+}
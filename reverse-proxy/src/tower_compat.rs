@@ -0,0 +1,173 @@
+//! Adapters between [`Service`] and [`tower::Service`], so that tower and tower-http middleware can be used.
+
+use std::{
+    error::Error as StdError,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::Error;
+use http::{Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Incoming};
+use tower::ServiceExt as _;
+
+use crate::{Outgoing, Service, service::IntoService};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// [`tower::Service`] made from [`Service`], see [`Service::into_tower`].
+///
+/// Cloning is cheap, clones share the same service.
+pub struct IntoTower<S: ?Sized> {
+    service: Arc<S>,
+}
+
+impl<S: ?Sized> IntoTower<S> {
+    pub fn new(service: Arc<S>) -> Self {
+        Self { service }
+    }
+}
+
+impl<S: ?Sized> Clone for IntoTower<S> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+        }
+    }
+}
+
+impl<S: Service + 'static> tower::Service<Request<Incoming>> for IntoTower<S> {
+    type Response = Response<Outgoing>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Outgoing>, Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        // Our services are always ready
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        Box::pin(self.service.clone().call_arc(req))
+    }
+}
+
+/// [`Service`] made from [`tower::Service`].
+///
+/// The tower service is cloned for each request as it needs `&mut self` to be called.
+#[derive(Clone, Debug)]
+pub struct FromTower<T> {
+    inner: T,
+}
+
+impl<T> FromTower<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, B> Service for FromTower<T>
+where
+    T: tower::Service<Request<Incoming>, Response = Response<B>> + Clone + Send + Sync,
+    T::Error: Into<BoxError>,
+    T::Future: Send,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+        let res = self
+            .inner
+            .clone()
+            .oneshot(req)
+            .await
+            .map_err(|err| Error::from_boxed(err.into()))?;
+        Ok(res.map(|body| body.map_err(|err| Error::from_boxed(err.into())).boxed()))
+    }
+}
+
+/// Marker of [`IntoService`] implementation for tower services.
+pub struct Tower<B>(PhantomData<B>);
+
+impl<T, B> IntoService<Tower<B>> for T
+where
+    T: tower::Service<Request<Incoming>, Response = Response<B>> + Clone + Send + Sync + 'static,
+    T::Error: Into<BoxError>,
+    T::Future: Send,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Service = FromTower<T>;
+
+    fn into_service(self) -> FromTower<T> {
+        FromTower::new(self)
+    }
+}
+
+#[tokio::test]
+async fn tower_layers() {
+    use std::time::Duration;
+
+    use http_body_util::{Empty, Full};
+    use hyper::client::conn::http1;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::{TcpListener, TcpStream};
+    use tower::{ServiceBuilder, timeout::TimeoutLayer};
+
+    use crate::{Server, Shutdown};
+
+    #[derive(Clone)]
+    struct Hello;
+    impl Service for Hello {
+        async fn call(&self, req: Request<Incoming>) -> Result<Response<Outgoing>, Error> {
+            if req.uri().path() == "/slow" {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Ok(Response::new(
+                Full::from("hello").map_err(Error::new).boxed(),
+            ))
+        }
+    }
+
+    // Our service wrapped in tower middleware
+    let stack = ServiceBuilder::new()
+        .layer(TimeoutLayer::new(Duration::from_millis(100)))
+        .map_response(|mut res: Response<Outgoing>| {
+            res.headers_mut().insert("x-layer", "1".parse().unwrap());
+            res
+        })
+        .service(Hello.into_tower());
+
+    let shutdown = Shutdown::new();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new()
+        .shutdown(shutdown.clone())
+        .bind_listener(listener)
+        .unwrap();
+    let server = tokio::spawn(server.serve(async move || Ok(stack.clone())));
+
+    let stream = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+    let (mut sender, conn) = http1::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let res = sender
+        .send_request(Request::get("/").body(Empty::<Bytes>::new()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["x-layer"], "1");
+    assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "hello");
+    // Timeout error is turned into an internal error response
+    let res = sender
+        .send_request(Request::get("/slow").body(Empty::<Bytes>::new()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 500);
+
+    shutdown.stop();
+    server.await.unwrap().unwrap();
+}